@@ -7,6 +7,8 @@ use chrono::{DateTime, SecondsFormat, Utc};
 use json_trait::ForeignMutableJson;
 use mime::Mime;
 use once_cell::sync::Lazy;
+use serde::de::value::StrDeserializer;
+use serde::de::{Deserialize, IntoDeserializer};
 use serde_json::{json, Map, Value};
 
 use crate::error::internal_error;
@@ -17,20 +19,41 @@ macro_rules! ns {
 	($ns:tt:) => { concat!(ns!($ns), "#") };
 	(as) => { "https://www.w3.org/ns/activitystreams" };
 	(ldp) => { "http://www.w3.org/ns/ldp" };
+	(sec) => { "https://w3id.org/security" };
+	(xsd) => { "http://www.w3.org/2001/XMLSchema" };
 }
 
+mod auth;
+mod collection;
+mod diff;
 mod handler;
 mod jsonld;
+mod memento;
+mod queue;
+mod resolve;
+mod signature;
 mod strip;
 
 pub use handler::*;
+pub use queue::run as run_queue;
+
+// Records of posts under `/post/`, the revision at or before a time
+pub const RECORD_PATH: &str = r"{url_decoration:([^-/]+-)?}{time:\d{4}-\d\d-\d\dT\d\d:\d\d(:\d\d(\.\d+)?)?Z}-{id:[^-/]+}";
 
 static CONTEXT: Lazy<Value> = Lazy::new(|| Value::String(ns!(as).to_string()));
+// For `publicKey` on actors
+static SECURITY_CONTEXT: Lazy<Value> = Lazy::new(|| Value::String("https://w3id.org/security/v1".to_string()));
+// Widely used properties missing from the ActivityStreams context
+static EXTENSION_CONTEXT: Lazy<Value> = Lazy::new(|| {
+	json!({
+		"manuallyApprovesFollowers": "as:manuallyApprovesFollowers"
+	})
+});
 // Setting base URLs for existing properties is technically not conformant. This context is to be provided opt-in.
 // If we ever host this somewhere, instead of replacing this with bare URL, context object with @import will be needed
 // to preserve the relative URL semantics.
 static GRAFT_CONTEXT: Lazy<Value> = Lazy::new(|| {
-	json!({ // Compact ids to bare oids
+	let mut context = json!({ // Compact ids to bare oids
 		ns!(as:actor): {
 			"@context": {
 				"@base": "../of/"
@@ -58,7 +81,10 @@ static GRAFT_CONTEXT: Lazy<Value> = Lazy::new(|| {
 				"@base": "../post/"
 			}
 		}
-	})
+	});
+	// Stored objects use the extension terms too
+	context.as_object_mut().expect("context object").extend(EXTENSION_CONTEXT.as_object().expect("context object").clone());
+	context
 });
 
 // Too bad macros can't easily do this.
@@ -73,6 +99,10 @@ enum SupportedActivity {
 	Delete,
 	#[serde(rename = "https://www.w3.org/ns/activitystreams#Follow")]
 	Follow,
+	#[serde(rename = "https://www.w3.org/ns/activitystreams#Accept")]
+	Accept,
+	#[serde(rename = "https://www.w3.org/ns/activitystreams#Reject")]
+	Reject,
 	#[serde(rename = "https://www.w3.org/ns/activitystreams#Add")]
 	Add,
 	#[serde(rename = "https://www.w3.org/ns/activitystreams#Remove")]
@@ -81,10 +111,19 @@ enum SupportedActivity {
 	Like,
 	#[serde(rename = "https://www.w3.org/ns/activitystreams#Block")]
 	Block,
+	#[serde(rename = "https://www.w3.org/ns/activitystreams#Announce")]
+	Announce,
 	#[serde(rename = "https://www.w3.org/ns/activitystreams#Undo")]
 	Undo
 }
 
+fn is_public(iri: &str) -> bool {
+	match iri {
+		ns!(as:Public) | "as:Public" | "Public" => true,
+		_ => false
+	}
+}
+
 fn get_id(object: &Map<String, Value>) -> Result<ObjectId, Error> {
 	object
 		.get("@id")
@@ -97,6 +136,96 @@ fn get_id(object: &Map<String, Value>) -> Result<ObjectId, Error> {
 		.map_err(ErrorBadRequest)
 }
 
+fn get_iri(object: &Map<String, Value>) -> Result<&str, Error> {
+	Ok(object.get("@id").ok_or(ErrorBadRequest("`id` is missing"))?.as_str().expect("valid JSON-LD"))
+}
+
+fn get_iris<'a>(object: &'a Map<String, Value>, prop: &str) -> impl Iterator<Item = &'a str> {
+	get_objects(object, prop).into_iter().flatten().filter_map(|object| object.get("@id").map(|id| id.as_str().expect("valid JSON-LD")))
+}
+
+// Activity types from the ActivityStreams vocabulary, whether we support them or not.
+// `Question` is left out, as it's normally posted like any other object.
+const ACTIVITY_TYPES: [&str; 29] = [
+	ns!(as:Activity),
+	ns!(as:IntransitiveActivity),
+	ns!(as:Accept),
+	ns!(as:Add),
+	ns!(as:Announce),
+	ns!(as:Arrive),
+	ns!(as:Block),
+	ns!(as:Create),
+	ns!(as:Delete),
+	ns!(as:Dislike),
+	ns!(as:Flag),
+	ns!(as:Follow),
+	ns!(as:Ignore),
+	ns!(as:Invite),
+	ns!(as:Join),
+	ns!(as:Leave),
+	ns!(as:Like),
+	ns!(as:Listen),
+	ns!(as:Move),
+	ns!(as:Offer),
+	ns!(as:Read),
+	ns!(as:Reject),
+	ns!(as:Remove),
+	ns!(as:TentativeAccept),
+	ns!(as:TentativeReject),
+	ns!(as:Travel),
+	ns!(as:Undo),
+	ns!(as:Update),
+	ns!(as:View)
+];
+
+fn is_activity(object: &Map<String, Value>) -> bool {
+	object.get("@type").and_then(Value::as_array).map_or(false, |types| types.iter().filter_map(Value::as_str).any(|ty| ACTIVITY_TYPES.contains(&ty)))
+}
+
+fn get_activity_type(object: &Map<String, Value>) -> Result<Option<SupportedActivity>, Error> {
+	Ok(object
+		.get("@type")
+		.ok_or(ErrorBadRequest("missing `type`"))?
+		.as_array()
+		.expect("expanded object")
+		.iter()
+		.map(|ty| ty.as_str().expect("expanded object"))
+		.find_map(|ty| {
+			let deserializer: StrDeserializer<serde::de::value::Error> = ty.into_deserializer();
+			SupportedActivity::deserialize(deserializer).ok()
+		}))
+}
+
+// Actors own themselves, everything else is owned by whoever it's attributed to.
+fn is_owned_by(object: &Map<String, Value>, actor: &str) -> bool {
+	object.get("@id").and_then(|id| id.as_str()) == Some(actor) || get_iris(object, ns!(as:attributedTo)).any(|author| author == actor)
+}
+
+// `followers` is the followers collection of the author, if the reader is one of them.
+fn is_addressed(object: &Map<String, Value>, reader: Option<&str>, followers: Option<&str>) -> bool {
+	[ns!(as:to), ns!(as:cc), ns!(as:bto), ns!(as:bcc), ns!(as:audience)]
+		.iter()
+		.flat_map(|prop| get_iris(object, prop))
+		.any(|recipient| is_public(recipient) || Some(recipient) == reader || Some(recipient) == followers)
+}
+
+// Blind recipients are only for delivery, so they're taken out of anything stored or served.
+fn take_blind_recipients(object: &mut Map<String, Value>) -> Vec<String> {
+	[ns!(as:bto), ns!(as:bcc)]
+		.iter()
+		.filter_map(|prop| object.remove(*prop))
+		.flat_map(|recipients| recipients.into_array().expect("expanded value"))
+		.filter_map(|recipient| recipient.get("@id").and_then(|id| id.as_str()).map(|id| id.to_string()))
+		.collect()
+}
+
+fn add_recipient(object: &mut Map<String, Value>, recipient: &str) {
+	let to = object.entry(ns!(as:to)).or_insert_with(|| json!([])).as_array_mut().expect("expanded value");
+	if to.iter().all(|to| to.get("@id").and_then(|id| id.as_str()) != Some(recipient)) {
+		to.push(json!({ "@id": recipient }));
+	}
+}
+
 fn is_collection(object: &Map<String, Value>) -> Result<bool, Error> {
 	Ok(object
 		.get("@type")
@@ -105,11 +234,24 @@ fn is_collection(object: &Map<String, Value>) -> Result<bool, Error> {
 		.expect("expanded object")
 		.iter()
 		.any(|ty| match ty.as_str() {
-			Some("Collection" | "OrderedCollection") => true,
+			Some(ns!(as:Collection) | ns!(as:OrderedCollection)) => true,
 			_ => false
 		}))
 }
 
+// Takes inline members out of an expanded collection, in the order they're listed.
+fn take_items(collection: &mut Map<String, Value>) -> Vec<String> {
+	let id = |item: &Value| item.get("@id").and_then(|id| id.as_str()).map(|id| id.to_string());
+	let mut items = Vec::new();
+	for item in [ns!(as:items), ns!(as:orderedItems)].iter().filter_map(|prop| collection.remove(*prop)).flat_map(|items| items.into_array().expect("expanded value")) {
+		match item.get("@list") {
+			Some(Value::Array(list)) => items.extend(list.iter().filter_map(id)),
+			_ => items.extend(id(&item))
+		}
+	}
+	items
+}
+
 fn get_objects<'a>(object: &'a Map<String, Value>, prop: &str) -> Option<impl Iterator<Item = &'a Map<String, Value>>> {
 	object
 		.get(prop)
@@ -137,7 +279,7 @@ fn take_objects(object: &mut Map<String, Value>, prop: &str) -> Option<impl Iter
 }
 
 fn copy_recipients(from: &Map<String, Value>, to: &mut Map<String, Value>) {
-	for key in ["to", "cc", "bto", "bcc", "audience"].iter().filter(|key| from.contains_key(**key)) {
+	for key in [ns!(as:to), ns!(as:cc), ns!(as:bto), ns!(as:bcc), ns!(as:audience)].iter().filter(|key| from.contains_key(**key)) {
 		if to.contains_key(*key) {
 			let to = to[*key].as_array_mut().expect("expanded value");
 			for from in from[*key].as_array().expect("expanded value") {
@@ -184,6 +326,82 @@ pub fn is_activitypub_request(head: &RequestHead) -> bool {
 	false
 }
 
+// Requests for a past revision of a post, per Memento (RFC 7089)
+pub fn is_datetime_negotiation(head: &RequestHead) -> bool {
+	head.headers.contains_key("Accept-Datetime")
+}
+
+// What's left of a deleted object. Both are expanded, so `formerType` always holds full type IRIs.
+fn tombstone(id: &str, old: &Map<String, Value>, timestamp: &Value) -> Map<String, Value> {
+	let former_types = old.get("@type").and_then(Value::as_array).map_or_else(Vec::new, |types| types.iter().map(|ty| json!({ "@id": ty })).collect());
+	let mut tombstone = Map::new();
+	tombstone.insert("@id".to_string(), id.into());
+	tombstone.insert("@type".to_string(), json!([ns!(as:Tombstone)]));
+	tombstone.insert(ns!(as:formerType).to_string(), Value::Array(former_types));
+	for prop in [ns!(as:published), ns!(as:updated), ns!(as:deleted)] {
+		tombstone.insert(prop.to_string(), timestamp.clone());
+	}
+	tombstone
+}
+
 fn datetime(time: DateTime<Utc>) -> Value {
 	json!({ "@type": "xsd:dateTime" , "@value": time.to_rfc3339_opts(SecondsFormat::Millis, true) })
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_tombstone() {
+		let timestamp = datetime("2021-12-22T18:29:00Z".parse().unwrap());
+		// Local objects and remote ones are both unstripped before they're deleted
+		let old = json!({ "@id": "https://example.org/post/a", "@type": [ns!(as:Note)], ns!(as:content): [{ "@value": "hi" }] });
+		let tombstone = tombstone("https://example.org/post/a", old.as_object().unwrap(), &timestamp);
+		assert_eq!(tombstone["@type"], json!([ns!(as:Tombstone)]));
+		assert_eq!(tombstone[ns!(as:formerType)], json!([{ "@id": ns!(as:Note) }]));
+		assert_eq!(tombstone[ns!(as:deleted)], timestamp);
+		assert!(!tombstone.contains_key(ns!(as:content)));
+	}
+
+	#[test]
+	fn test_is_owned_by() {
+		let post = json!({ "@id": "https://example.org/post/a", ns!(as:attributedTo): [{ "@id": "https://example.org/of/a" }] });
+		assert!(is_owned_by(post.as_object().unwrap(), "https://example.org/of/a"));
+		assert!(!is_owned_by(post.as_object().unwrap(), "https://example.org/of/b"));
+		// Actors own themselves
+		let actor = json!({ "@id": "https://example.org/of/b" });
+		assert!(is_owned_by(actor.as_object().unwrap(), "https://example.org/of/b"));
+	}
+
+	#[test]
+	fn test_is_addressed() {
+		let public = json!({ ns!(as:to): [{ "@id": ns!(as:Public) }] });
+		assert!(is_addressed(public.as_object().unwrap(), None, None));
+
+		let followers_only = json!({ ns!(as:to): [{ "@id": "https://example.org/followers/a" }] });
+		assert!(!is_addressed(followers_only.as_object().unwrap(), None, None));
+		assert!(!is_addressed(followers_only.as_object().unwrap(), Some("https://example.org/of/b"), None));
+		assert!(is_addressed(followers_only.as_object().unwrap(), Some("https://example.org/of/b"), Some("https://example.org/followers/a")));
+
+		let direct = json!({ ns!(as:cc): [{ "@id": "https://example.org/of/b" }] });
+		assert!(is_addressed(direct.as_object().unwrap(), Some("https://example.org/of/b"), None));
+		assert!(!is_addressed(direct.as_object().unwrap(), Some("https://example.org/of/c"), Some("https://example.org/followers/a")));
+		assert!(!is_addressed(direct.as_object().unwrap(), None, None));
+	}
+
+	#[test]
+	fn test_take_blind_recipients() {
+		let mut object = json!({
+			ns!(as:to): [{ "@id": "https://example.org/of/a" }],
+			ns!(as:bto): [{ "@id": "https://example.org/of/b" }],
+			ns!(as:bcc): [{ "@id": "https://example.org/of/c" }]
+		});
+		let object = object.as_object_mut().unwrap();
+		assert_eq!(take_blind_recipients(object), vec!["https://example.org/of/b".to_string(), "https://example.org/of/c".to_string()]);
+		assert!(!object.contains_key(ns!(as:bto)));
+		assert!(!object.contains_key(ns!(as:bcc)));
+		assert_eq!(object[ns!(as:to)], json!([{ "@id": "https://example.org/of/a" }]));
+		assert!(take_blind_recipients(object).is_empty());
+	}
+}
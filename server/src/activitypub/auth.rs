@@ -0,0 +1,105 @@
+use actix_web::dev::Payload;
+use actix_web::error::{Error as ActixError, ErrorUnauthorized};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::Method;
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+use mongodb::bson::Bson;
+use mongodb::Client;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::rand::rand_bytes;
+
+use super::signature::verify;
+use crate::db::get_token_owner;
+use crate::error::internal_error;
+use crate::util::{from_reference, to_reference, ObjectId};
+
+// Returns a new bearer token along with the hash to store.
+pub fn generate_token() -> Result<(String, String), ErrorStack> {
+	let mut token = [0; 32];
+	rand_bytes(&mut token)?;
+	let token = hex(&token);
+	let hash = hash_token(&token)?;
+	Ok((token, hash))
+}
+
+fn hash_token(token: &str) -> Result<String, ErrorStack> {
+	Ok(hex(&hash(MessageDigest::sha256(), token.as_bytes())?))
+}
+
+// The stored hash of the bearer token a request carries, if any
+pub fn bearer_hash(req: &HttpRequest) -> Result<Option<String>, ActixError> {
+	let authorization = match req.headers().get(AUTHORIZATION) {
+		Some(authorization) => authorization,
+		None => return Ok(None)
+	};
+	let token = authorization
+		.to_str()
+		.ok()
+		.and_then(|authorization| authorization.strip_prefix("Bearer "))
+		.ok_or(ErrorUnauthorized("unsupported `Authorization`"))?;
+	hash_token(token.trim()).map(Some).map_err(internal_error)
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Whoever made a request: the signer of a signed request, or the local actor a bearer token is issued to.
+// Requests with bad credentials are rejected rather than treated as anonymous.
+// Signatures are checked without the body, so they're only good for reading. Anything else takes a bearer token.
+pub struct Requester(pub Option<String>);
+
+impl Requester {
+	pub fn is(&self, actor: &ObjectId) -> bool {
+		self.0.as_deref().map_or(false, |requester| to_reference(requester) == Bson::from(actor))
+	}
+}
+
+impl FromRequest for Requester {
+	type Error = ActixError;
+	type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+	fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+		let req = req.clone();
+		Box::pin(async move {
+			let db = req.app_data::<Data<Client>>().expect("database is registered");
+			if let Some(hash) = bearer_hash(&req)? {
+				let owner = get_token_owner(&hash, db).await?.ok_or(ErrorUnauthorized("invalid token"))?;
+				return Ok(Requester(from_reference(&(&owner).into(), "of")));
+			}
+			if !req.headers().contains_key("Signature") {
+				return Ok(Requester(None));
+			}
+			if req.method() != Method::GET && req.method() != Method::HEAD {
+				return Err(ErrorUnauthorized("signed requests may only read, use a bearer token"));
+			}
+			verify(&req, None, db).await.map(|owner| Requester(Some(owner)))
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use actix_web::test::TestRequest;
+
+	#[test]
+	fn test_generate_token() {
+		let (token, hash) = generate_token().unwrap();
+		assert_eq!(token.len(), 64);
+		assert_eq!(hash_token(&token).unwrap(), hash);
+		assert_ne!(generate_token().unwrap().0, token);
+	}
+
+	#[test]
+	fn test_bearer_hash() {
+		let (token, hash) = generate_token().unwrap();
+		let req = TestRequest::post().append_header((AUTHORIZATION, format!("Bearer {}", token))).to_http_request();
+		assert_eq!(bearer_hash(&req).unwrap(), Some(hash));
+		assert_eq!(bearer_hash(&TestRequest::post().to_http_request()).unwrap(), None);
+		assert!(bearer_hash(&TestRequest::post().append_header((AUTHORIZATION, "Basic Zm9vOmJhcg==")).to_http_request()).is_err());
+	}
+}
@@ -0,0 +1,130 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use chrono::SecondsFormat;
+use mongodb::bson::oid::ObjectId;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Map, Value};
+
+use crate::db::{Cursor, Position, PAGE_SIZE};
+
+// `?page` alone is the first page, `before` and `after` move from there.
+#[derive(Deserialize)]
+pub struct PageQuery {
+	page: Option<String>,
+	before: Option<Position>,
+	after: Option<Position>
+}
+
+// Positions in URLs are the time followed by the id, e.g. `2021-12-22T18:29:00.000Z_61c36e2ca0f9f2e2a4f1d3b1`.
+impl Display for Position {
+	fn fmt(&self, f: &mut Formatter) -> FmtResult {
+		write!(f, "{}_{}", self.time.to_rfc3339_opts(SecondsFormat::Millis, true), self.id.to_hex())
+	}
+}
+
+impl<'de> Deserialize<'de> for Position {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let position = String::deserialize(deserializer)?;
+		let (time, id) = position.split_once('_').ok_or_else(|| D::Error::custom("position is missing an id"))?;
+		Ok(Position { time: time.parse().map_err(D::Error::custom)?, id: ObjectId::parse_str(id).map_err(D::Error::custom)? })
+	}
+}
+
+impl PageQuery {
+	// `None` if the collection itself is requested
+	pub fn cursor(&self) -> Option<Cursor> {
+		self.page.as_ref()?;
+		Some(match (self.before, self.after) {
+			(Some(before), _) => Cursor::Before(before),
+			(None, Some(after)) => Cursor::After(after),
+			(None, None) => Cursor::Newest
+		})
+	}
+}
+
+pub fn page_id(id: &str, cursor: Cursor) -> String {
+	match cursor {
+		Cursor::Newest => format!("{}?page", id),
+		Cursor::Before(position) => format!("{}?page&before={}", id, position),
+		Cursor::After(position) => format!("{}?page&after={}", id, position)
+	}
+}
+
+pub fn ordered_collection(id: &str, total: u64) -> Map<String, Value> {
+	let mut collection = Map::new();
+	collection.insert("@id".to_string(), id.into());
+	collection.insert("@type".to_string(), json!([ns!(as:OrderedCollection)]));
+	collection.insert(ns!(as:totalItems).to_string(), json!([{ "@type": ns!(xsd:nonNegativeInteger), "@value": total }]));
+	collection.insert(ns!(as:first).to_string(), json!([{ "@id": page_id(id, Cursor::Newest) }]));
+	collection
+}
+
+// `items` are expanded objects along with the time they're ordered by, newest first.
+pub fn ordered_collection_page(id: &str, cursor: Cursor, items: Vec<(Position, Value)>) -> Map<String, Value> {
+	filtered_collection_page(id, cursor, items.into_iter().map(|(time, item)| (time, Some(item))).collect())
+}
+
+// Same as above, but items hidden from the reader are `None`. They still count for the links to other pages.
+pub fn filtered_collection_page(id: &str, cursor: Cursor, items: Vec<(Position, Option<Value>)>) -> Map<String, Value> {
+	let mut page = Map::new();
+	page.insert("@id".to_string(), page_id(id, cursor).into());
+	page.insert("@type".to_string(), json!([ns!(as:OrderedCollectionPage)]));
+	page.insert(ns!(as:partOf).to_string(), json!([{ "@id": id }]));
+	if let (Some((newest, _)), Some((oldest, _))) = (items.first(), items.last()) {
+		// Going back from the newest page, there's always something newer
+		if items.len() as i64 == PAGE_SIZE || matches!(cursor, Cursor::After(_)) {
+			page.insert(ns!(as:next).to_string(), json!([{ "@id": page_id(id, Cursor::Before(*oldest)) }]));
+		}
+		if cursor != Cursor::Newest {
+			page.insert(ns!(as:prev).to_string(), json!([{ "@id": page_id(id, Cursor::After(*newest)) }]));
+		}
+	}
+	page.insert(ns!(as:orderedItems).to_string(), json!([{ "@list": items.into_iter().filter_map(|(_, item)| item).collect::<Vec<_>>() }]));
+	page
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::{DateTime, Utc};
+
+	#[test]
+	fn test_cursor() {
+		let query = |query| actix_web::web::Query::<PageQuery>::from_query(query).unwrap().into_inner().cursor();
+		let time = "2021-12-22T18:29:00Z".parse().unwrap();
+		assert_eq!(query(""), None);
+		assert_eq!(query("page"), Some(Cursor::Newest));
+		let id = ObjectId::parse_str("61c36e2ca0f9f2e2a4f1d3b1").unwrap();
+		assert_eq!(query("page&before=2021-12-22T18:29:00.000Z_61c36e2ca0f9f2e2a4f1d3b1"), Some(Cursor::Before(Position { time, id })));
+		assert_eq!(query("page&after=2021-12-22T18:29:00.000Z_61c36e2ca0f9f2e2a4f1d3b1"), Some(Cursor::After(Position { time, id })));
+		assert!(actix_web::web::Query::<PageQuery>::from_query("page&before=2021-12-22T18:29:00.000Z").is_err());
+	}
+
+	#[test]
+	fn test_filtered_page() {
+		let time = "2021-12-22T18:29:00Z".parse::<DateTime<Utc>>().unwrap();
+		let id = ObjectId::parse_str("61c36e2ca0f9f2e2a4f1d3b1").unwrap();
+		let items = (0..PAGE_SIZE).map(|i| (Position { time: time - chrono::Duration::minutes(i), id }, None)).collect();
+		let page = filtered_collection_page("https://example.org/by/a", Cursor::Newest, items);
+		assert_eq!(page[ns!(as:orderedItems)], json!([{ "@list": [] }]));
+		assert_eq!(page[ns!(as:next)], json!([{ "@id": "https://example.org/by/a?page&before=2021-12-22T18:10:00.000Z_61c36e2ca0f9f2e2a4f1d3b1" }]));
+	}
+
+	#[test]
+	fn test_page() {
+		let time = "2021-12-22T18:29:00Z".parse::<DateTime<Utc>>().unwrap();
+		let position = Position { time, id: ObjectId::parse_str("61c36e2ca0f9f2e2a4f1d3b1").unwrap() };
+		let page = ordered_collection_page("https://example.org/followers/a", Cursor::Before(position), vec![(position, json!({ "@id": "https://example.org/of/b" }))]);
+		assert_eq!(
+			Value::Object(page),
+			json!({
+				"@id": "https://example.org/followers/a?page&before=2021-12-22T18:29:00.000Z_61c36e2ca0f9f2e2a4f1d3b1",
+				"@type": [ns!(as:OrderedCollectionPage)],
+				ns!(as:partOf): [{ "@id": "https://example.org/followers/a" }],
+				ns!(as:prev): [{ "@id": "https://example.org/followers/a?page&after=2021-12-22T18:29:00.000Z_61c36e2ca0f9f2e2a4f1d3b1" }],
+				ns!(as:orderedItems): [{ "@list": [{ "@id": "https://example.org/of/b" }] }]
+			})
+		);
+	}
+}
@@ -0,0 +1,102 @@
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+// Prose is easier to review as a line diff than as the whole value before and after.
+const TEXT_PROPERTIES: [&str; 3] = ["content", "summary", "name"];
+
+// Times are in the same form as in record URLs. Without `from`, `to` is compared with the revision before it.
+#[derive(Deserialize)]
+pub struct DiffQuery {
+	pub from: Option<String>,
+	pub to: String
+}
+
+// A unified diff of the two texts as a single hunk, with the whole text as context
+fn line_diff(old: &str, new: &str) -> String {
+	let old = old.lines().collect::<Vec<_>>();
+	let new = new.lines().collect::<Vec<_>>();
+	// Length of the longest common subsequence of the lines from each position onwards
+	let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+	for i in (0..old.len()).rev() {
+		for j in (0..new.len()).rev() {
+			common[i][j] = if old[i] == new[j] { common[i + 1][j + 1] + 1 } else { common[i + 1][j].max(common[i][j + 1]) };
+		}
+	}
+
+	let start = |len: usize| if len == 0 { 0 } else { 1 };
+	let mut diff = format!("@@ -{},{} +{},{} @@\n", start(old.len()), old.len(), start(new.len()), new.len());
+	let (mut i, mut j) = (0, 0);
+	while i < old.len() || j < new.len() {
+		if i < old.len() && j < new.len() && old[i] == new[j] {
+			diff += &format!(" {}\n", old[i]);
+			i += 1;
+			j += 1;
+		} else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+			diff += &format!("-{}\n", old[i]);
+			i += 1;
+		} else {
+			diff += &format!("+{}\n", new[j]);
+			j += 1;
+		}
+	}
+	diff
+}
+
+// Both revisions are expected compacted with the same context.
+pub fn diff(from: &Map<String, Value>, to: &Map<String, Value>) -> Map<String, Value> {
+	let mut added = Map::new();
+	let mut removed = Map::new();
+	let mut changed = Map::new();
+	let mut text = Map::new();
+	for (key, old) in from {
+		match to.get(key) {
+			None => {
+				removed.insert(key.clone(), old.clone());
+			}
+			Some(new) if new != old => {
+				if let (true, Some(old), Some(new)) = (TEXT_PROPERTIES.contains(&key.as_str()), old.as_str(), new.as_str()) {
+					text.insert(key.clone(), line_diff(old, new).into());
+				}
+				changed.insert(key.clone(), json!({ "from": old, "to": new }));
+			}
+			_ => ()
+		}
+	}
+	for (key, new) in to {
+		if !from.contains_key(key) {
+			added.insert(key.clone(), new.clone());
+		}
+	}
+	let mut diff = Map::new();
+	diff.insert("added".to_string(), added.into());
+	diff.insert("removed".to_string(), removed.into());
+	diff.insert("changed".to_string(), changed.into());
+	diff.insert("text".to_string(), text.into());
+	diff
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_diff() {
+		let from = json!({ "id": "a", "content": "one\ntwo\n", "summary": "gone", "tag": ["x"] });
+		let to = json!({ "id": "a", "content": "one\nthree\n", "name": "new", "tag": ["y"] });
+		let diff = diff(from.as_object().unwrap(), to.as_object().unwrap());
+		assert_eq!(diff["added"], json!({ "name": "new" }));
+		assert_eq!(diff["removed"], json!({ "summary": "gone" }));
+		assert_eq!(
+			diff["changed"],
+			json!({ "content": { "from": "one\ntwo\n", "to": "one\nthree\n" }, "tag": { "from": ["x"], "to": ["y"] } })
+		);
+		assert_eq!(diff["text"], json!({ "content": "@@ -1,2 +1,2 @@\n one\n-two\n+three\n" }));
+	}
+
+	#[test]
+	fn test_line_diff() {
+		assert_eq!(line_diff("a\nb\nc", "a\nc\nd"), "@@ -1,3 +1,3 @@\n a\n-b\n c\n+d\n");
+		assert_eq!(line_diff("", "a"), "@@ -0,0 +1,1 @@\n+a\n");
+		assert_eq!(line_diff("a", ""), "@@ -1,1 +0,0 @@\n-a\n");
+	}
+}
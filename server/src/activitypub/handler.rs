@@ -1,129 +1,497 @@
 use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::iter::once;
 
-use actix_web::error::{Error as ActixError, ErrorBadRequest, ErrorNotFound};
+use actix_web::dev::{Path as RouterPath, ResourceDef};
+use actix_web::error::{Error as ActixError, ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorNotFound, ErrorNotImplemented, ErrorUnauthorized};
 use actix_web::http::StatusCode;
-use actix_web::web::{Data, Json, Path};
-use actix_web::{HttpRequest, Responder};
-use chrono::Utc;
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::{CustomizeResponder, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use json_ld_rs::JsonLdOptions;
 use json_trait::{json, BuildableJson};
+use mongodb::bson::Bson;
 use mongodb::Client;
-use serde::de::value::StrDeserializer;
-use serde::de::{Deserialize, IntoDeserializer};
+use once_cell::sync::Lazy;
 use serde_json::{Map, Value};
 
+use super::auth::{bearer_hash, generate_token, Requester};
+use super::collection::*;
+use super::diff::{diff, DiffQuery};
 use super::jsonld::*;
+use super::memento;
+use super::queue::enqueue;
+use super::resolve::resolve;
+use super::signature::{generate_keypair, get_keypair, same_origin, Signed};
 use super::strip::*;
 use super::*;
-use crate::db::{get, get_children, get_record, get_replies, insert};
-use crate::error::internal_error;
+use crate::db::{
+	accept_follow, claim_name, count_followers, count_following, count_inbox, count_items, count_liked, count_likes, count_outbox, count_revisions, count_shares, delete_block,
+	delete_follow, delete_item, delete_like, delete_share, deliver, get, get_actor_by_name, get_blind_recipients, get_blocked, get_by_iri, get_children, get_first_record,
+	get_followers, get_following, get_inbox, get_items, get_liked, get_likes, get_local_followers, get_outbox, get_record, get_replies, get_revisions, get_shares, insert,
+	insert_blind_recipients, insert_block, insert_follow, insert_item, insert_keypair, insert_like, insert_share, insert_token, is_blocked, is_follower, reject_follow,
+	release_name, set_host_dead, take_token, Cursor, Position, ACTOR_TYPES, PAGE_SIZE
+};
+use crate::error::{internal_error, Gone};
 use crate::util::*;
 
-pub async fn account(req: HttpRequest, id: ObjectId, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+// References in a stored object, in the form relations keep them
+fn get_references(object: &Map<String, Value>, keys: &[&str]) -> Vec<Bson> {
+	keys.iter()
+		.filter_map(|key| object.get(*key))
+		.flat_map(|value| match value {
+			Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+			value => value.as_str().into_iter().collect::<Vec<_>>()
+		})
+		.map(|owner| owner.parse().map_or_else(|_| Bson::String(owner.to_string()), Bson::ObjectId))
+		.collect()
+}
+
+// Actors who may have blocked someone from seeing a stored object: itself if it's an actor, and its author.
+fn get_owners(object: &Map<String, Value>) -> Vec<Bson> {
+	get_references(object, &["id", "attributedTo", "actor"])
+}
+
+// Those who are blocked can't see anything from the blockers, so they get the same response as if it didn't exist.
+async fn hide_from_blocked(object: &Map<String, Value>, requester: &Requester, db: &Client) -> Result<(), ActixError> {
+	match &requester.0 {
+		Some(requester) if is_blocked(get_owners(object), to_reference(requester), db).await? => Err(ErrorNotFound("")),
+		_ => Ok(())
+	}
+}
+
+// Public objects are for everyone. Others are for their authors and those they're addressed to, by name or as followers of the author.
+async fn is_visible(req: &HttpRequest, object: &Map<String, Value>, requester: &Requester, db: &Client) -> Result<bool, ActixError> {
+	let authors = get_references(object, &["attributedTo", "actor"]);
+	let mut followers = None;
+	if let Some(reader) = requester.0.as_deref() {
+		let reader = to_reference(reader);
+		match &reader {
+			Bson::ObjectId(reader) if crate::ADMINS.contains(&reader.to_hex()) => return Ok(true),
+			_ if authors.contains(&reader) => return Ok(true),
+			_ => ()
+		}
+		if let Some(id) = object.get("id").and_then(Value::as_str).and_then(|id| id.parse::<ObjectId>().ok()) {
+			if get_blind_recipients(&id, db).await?.iter().any(|recipient| to_reference(recipient) == reader) {
+				return Ok(true);
+			}
+		}
+		for author in &authors {
+			if let Bson::ObjectId(author) = author {
+				if is_follower(reader.clone(), &ObjectId::from(*author), db).await? {
+					followers = Some(Url::try_from(req)?.join(&format!("/followers/{}", author.to_hex())).map_err(internal_error)?.to_string());
+					break;
+				}
+			}
+		}
+	}
+	let object = unstrip_object(object.clone(), &json_ld_options(req)?).await.map_err(internal_error)?;
+	Ok(is_addressed(&object, requester.0.as_deref(), followers.as_deref()))
+}
+
+// Deleted objects are gone for good, only their Tombstone remains.
+async fn check_deleted(req: &HttpRequest, object: &Map<String, Value>) -> Result<(), ActixError> {
+	if object.get("type").and_then(Value::as_str) != Some("Tombstone") {
+		return Ok(());
+	}
+	let options = json_ld_options(req)?;
+	let context = context(object, req.head())?;
+	let tombstone = unstrip_object(object.clone(), &options).await.map_err(internal_error)?;
+	Err(Gone(compact_object(&tombstone, context, &options).await.map_err(internal_error)?).into())
+}
+
+// Anything a requester can't see is served as if it didn't exist.
+// A Tombstone has no audience of its own, so it's for whoever could see the revision it replaced.
+async fn check_visible(req: &HttpRequest, object: &Map<String, Value>, requester: &Requester, db: &Client) -> Result<(), ActixError> {
+	let former = match object.get("id").and_then(Value::as_str).and_then(|id| id.parse::<ObjectId>().ok()) {
+		Some(id) if object.get("type").and_then(Value::as_str) == Some("Tombstone") => get_record(&id, &(get_updated(object)? - Duration::milliseconds(1)), db).await?,
+		_ => None
+	};
+	let object = former.as_ref().unwrap_or(object);
+	hide_from_blocked(object, requester, db).await?;
+	if is_visible(req, object, requester, db).await? {
+		Ok(())
+	} else {
+		Err(ErrorNotFound(""))
+	}
+}
+
+pub async fn account(req: HttpRequest, id: ObjectId, requester: Requester, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
 	let mut account = get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	check_deleted(&req, &account).await?;
+	hide_from_blocked(&account, &requester, &db).await?;
+	let public_key = match account.get("type").and_then(Value::as_str) {
+		Some(ty) if ACTOR_TYPES.contains(&ty) => Some(get_keypair(&id, &db).await?.0),
+		_ => None
+	};
 	let options = json_ld_options(&req)?;
-	let context = context(&account, req.head())?;
-	account = unstrip_actor(account, &options).await.map_err(internal_error)?;
+	let mut context = context(&account, req.head())?;
+	// `publicKey` is the only term from the security context
+	let extensions = [public_key.as_ref().map(|_| &*SECURITY_CONTEXT), Some(&*EXTENSION_CONTEXT)];
+	for ctx in extensions.iter().copied().flatten() {
+		if !context.contains(ctx) {
+			context.push(ctx.clone());
+		}
+	}
+	account = unstrip_actor(account, public_key, &options).await.map_err(internal_error)?;
 	Ok(Json(compact_object(&account, context, &options).await.map_err(internal_error)?))
 }
 
-pub async fn inbox(req: HttpRequest, id: ObjectId, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
-	let mut inbox = Map::new();
+// Inboxes are private, so only their owner can read them.
+pub async fn inbox(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, requester: Requester, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	if requester.0.is_none() {
+		return Err(ErrorUnauthorized(""));
+	} else if !requester.is(&id) {
+		return Err(ErrorForbidden(""));
+	}
+	let url = Url::try_from(&req)?.to_string();
+	let cursor = match query.cursor() {
+		Some(cursor) => cursor,
+		None => return serve_collection(&req, ordered_collection(&url, count_inbox(&id, &db).await?)).await
+	};
+
 	let options = json_ld_options(&req)?;
-	let context = context(&inbox, req.head())?;
-	inbox = unstrip_object(inbox, &options).await.map_err(internal_error)?;
+	let mut items = Vec::new();
+	for (position, activity) in get_inbox(&id, cursor, &db).await? {
+		let stored = match &activity {
+			Bson::ObjectId(oid) => get(&ObjectId::from(*oid), &db).await?,
+			Bson::String(iri) => get_by_iri(iri, &db).await?,
+			_ => None
+		};
+		let item = match stored {
+			Some(stored) => Value::Object(unstrip_object(stored, &options).await.map_err(internal_error)?),
+			None => json!(Value, { "@id": from_reference(&activity, "activity").ok_or_else(|| internal_error("bad reference"))? })
+		};
+		items.push((position, item));
+	}
+	serve_collection(&req, ordered_collection_page(&url, cursor, items)).await
+}
 
-	// inbox["items"] = Value::Array(get(&id, &db.collection("activities")).await?);
+pub async fn outbox(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, requester: Requester, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	let account = get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	hide_from_blocked(&account, &requester, &db).await?;
+	let url = Url::try_from(&req)?;
+	let cursor = match query.cursor() {
+		Some(cursor) => cursor,
+		None => {
+			let reader = requester.0.as_deref().map(to_reference);
+			let total = match reader {
+				// The author and admins see everything
+				Some(Bson::ObjectId(reader)) if requester.is(&id) || crate::ADMINS.contains(&reader.to_hex()) => Some(count_outbox(&id, None, &db).await?),
+				// Anyone else sees just the public ones
+				None => Some(count_outbox(&id, Some(vec![ns!(as:Public), "as:Public", "Public"]), &db).await?),
+				// Those the reader may also see depend on too much to count in the database
+				Some(_) => None
+			};
+			let mut collection = ordered_collection(url.as_str(), total.unwrap_or_default());
+			if total.is_none() {
+				collection.remove(ns!(as:totalItems));
+			}
+			return serve_collection(&req, collection).await;
+		}
+	};
 
-	Ok(Json(compact_object(&inbox, context, &options).await.map_err(internal_error)?))
+	let options = json_ld_options(&req)?;
+	let mut items = Vec::new();
+	for (position, activity) in get_outbox(&id, cursor, &db).await? {
+		let item = if is_visible(&req, &activity, &requester, &db).await? {
+			Some(Value::Object(unstrip_object(activity, &options).await.map_err(internal_error)?))
+		} else {
+			None
+		};
+		items.push((position, item));
+	}
+	serve_collection(&req, filtered_collection_page(url.as_str(), cursor, items)).await
 }
 
-pub async fn outbox(req: HttpRequest, id: ObjectId, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
-	let mut outbox = Map::new();
-	let options = json_ld_options(&req)?;
-	let context = context(&outbox, req.head())?;
-	outbox = unstrip_object(outbox, &options).await.map_err(internal_error)?;
+fn reference_items(items: Vec<(Position, Bson)>, prefix: &str) -> Result<Vec<(Position, Value)>, ActixError> {
+	items
+		.into_iter()
+		.map(|(position, item)| Ok((position, json!(Value, { "@id": from_reference(&item, prefix).ok_or_else(|| internal_error("bad reference"))? }))))
+		.collect()
+}
+
+async fn serve_collection(req: &HttpRequest, collection: Map<String, Value>) -> Result<Json<Map<String, Value>>, ActixError> {
+	let options = json_ld_options(req)?;
+	let context = context(&Map::new(), req.head())?;
+	Ok(Json(compact_object(&collection, context, &options).await.map_err(internal_error)?))
+}
+
+pub async fn followers(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	let url = Url::try_from(&req)?.to_string();
+	let collection = match query.cursor() {
+		None => ordered_collection(&url, count_followers(&id, &db).await?),
+		Some(cursor) => ordered_collection_page(&url, cursor, reference_items(get_followers(&id, cursor, &db).await?, "of")?)
+	};
+	serve_collection(&req, collection).await
+}
+
+pub async fn following(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	let url = Url::try_from(&req)?.to_string();
+	let collection = match query.cursor() {
+		None => ordered_collection(&url, count_following(&id, &db).await?),
+		Some(cursor) => ordered_collection_page(&url, cursor, reference_items(get_following(&id, cursor, &db).await?, "of")?)
+	};
+	serve_collection(&req, collection).await
+}
+
+pub async fn likes(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	let url = Url::try_from(&req)?.to_string();
+	let collection = match query.cursor() {
+		None => ordered_collection(&url, count_likes(&id, &db).await?),
+		Some(cursor) => ordered_collection_page(&url, cursor, reference_items(get_likes(&id, cursor, &db).await?, "of")?)
+	};
+	serve_collection(&req, collection).await
+}
+
+pub async fn liked(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	let url = Url::try_from(&req)?.to_string();
+	let collection = match query.cursor() {
+		None => ordered_collection(&url, count_liked(&id, &db).await?),
+		Some(cursor) => ordered_collection_page(&url, cursor, reference_items(get_liked(&id, cursor, &db).await?, "post")?)
+	};
+	serve_collection(&req, collection).await
+}
 
-	// outbox["items"] = Value::Array(get(&id, &db.collection("activities")).await?);
+pub async fn shares(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	let url = Url::try_from(&req)?.to_string();
+	let collection = match query.cursor() {
+		None => ordered_collection(&url, count_shares(&id, &db).await?),
+		Some(cursor) => ordered_collection_page(&url, cursor, reference_items(get_shares(&id, cursor, &db).await?, "activity")?)
+	};
+	serve_collection(&req, collection).await
+}
 
-	Ok(Json(compact_object(&outbox, context, &options).await.map_err(internal_error)?))
+// Post URLs are their own TimeGates, so what they serve depends on `Accept-Datetime`.
+pub async fn post(
+	req: HttpRequest, id: ObjectId, query: Query<PageQuery>, requester: Requester, db: Data<Client>
+) -> Result<CustomizeResponder<Json<Map<String, Value>>>, ActixError> {
+	let links = memento_links(&req, &id)?;
+	Ok(get_post(req, id, query, requester, db).await?.customize().insert_header(("Vary", "Accept-Datetime")).insert_header(("Link", links)))
 }
 
-pub async fn post(req: HttpRequest, id: ObjectId, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+pub async fn get_post(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, requester: Requester, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
 	let mut post = get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	check_visible(&req, &post, &requester, &db).await?;
+	check_deleted(&req, &post).await?;
+	let blocked = get_blocked(get_owners(&post), &db).await?;
+	let is_unblocked = |object: &Map<String, Value>| get_references(object, &["attributedTo", "actor"]).iter().all(|author| !blocked.contains(author));
 	let options = json_ld_options(&req)?;
 	let context = context(&post, req.head())?;
 	post = unstrip_object(post, &options).await.map_err(internal_error)?;
-	let mut reverse = post.entry("@reverse").or_insert(json!(Value, {})).as_object_mut().unwrap();
-	let mut replies = reverse.entry(ns!(as:inReplyTo)).or_insert(json!(Value, [])).as_array_mut().unwrap();
-	replies.extend(
-		get_replies(&id, &db)
-			.await?
-			.into_iter()
-			.map(|mut reply| {
-				reply.remove("inReplyTo");
-				reply
-			})
-			.map(Value::Object)
-	);
-	let mut children = reverse.entry(ns!(as:context)).or_insert(json!(Value, [])).as_array_mut().unwrap();
-	children.extend(
-		get_children(&id, &db)
-			.await?
-			.into_iter()
-			.map(|mut child| {
-				child.remove("context");
-				child
-			})
-			.map(Value::Object)
-	);
+	if is_collection(&post)? {
+		let url = get_iri(&post)?.to_string();
+		match query.cursor() {
+			Some(cursor) => return serve_collection(&req, ordered_collection_page(&url, cursor, reference_items(get_items(&id, cursor, &db).await?, "post")?)).await,
+			None => post.extend(ordered_collection(&url, count_items(&id, &db).await?).into_iter().filter(|(key, _)| !key.starts_with('@')))
+		}
+	}
+	let likes = req.url_for("likes", once(id.to_string())).map_err(internal_error)?.to_string();
+	post.insert(ns!(as:likes).to_string(), Value::Array(vec![Value::Object(ordered_collection(&likes, count_likes(&id, &db).await?))]));
+	let shares = req.url_for("shares", once(id.to_string())).map_err(internal_error)?.to_string();
+	post.insert(ns!(as:shares).to_string(), Value::Array(vec![Value::Object(ordered_collection(&shares, count_shares(&id, &db).await?))]));
+	let mut replies = Vec::new();
+	for mut reply in get_replies(&id, &db).await? {
+		if is_unblocked(&reply) && is_visible(&req, &reply, &requester, &db).await? {
+			reply.remove("inReplyTo");
+			replies.push(Value::Object(reply));
+		}
+	}
+	let mut children = Vec::new();
+	for mut child in get_children(&id, &db).await? {
+		if is_unblocked(&child) && is_visible(&req, &child, &requester, &db).await? {
+			child.remove("context");
+			children.push(Value::Object(child));
+		}
+	}
+	let reverse = post.entry("@reverse").or_insert(json!(Value, {})).as_object_mut().unwrap();
+	reverse.entry(ns!(as:inReplyTo)).or_insert(json!(Value, [])).as_array_mut().unwrap().extend(replies);
+	reverse.entry(ns!(as:context)).or_insert(json!(Value, [])).as_array_mut().unwrap().extend(children);
 	Ok(Json(compact_object(&post, context, &options).await.map_err(internal_error)?))
 }
 
-pub async fn activity(req: HttpRequest, id: ObjectId, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+pub async fn activity(req: HttpRequest, id: ObjectId, requester: Requester, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
 	let mut post = get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+	// Blocks are private
+	if post.get("type").and_then(Value::as_str) == Some("Block") {
+		return Err(ErrorNotFound(""));
+	}
+	check_visible(&req, &post, &requester, &db).await?;
+	check_deleted(&req, &post).await?;
 	let options = json_ld_options(&req)?;
 	let context = context(&post, req.head())?;
 	post = unstrip_object(post, &options).await.map_err(internal_error)?;
 	Ok(Json(compact_object(&post, context, &options).await.map_err(internal_error)?))
 }
 
-pub async fn record(req: HttpRequest, path: Path<((), String)>, id: ObjectId, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+// Visibility of past records follows the latest one, so that restricting an object also restricts its history.
+fn parse_record_time(time: &str) -> Result<DateTime<Utc>, ActixError> {
 	// Omitting second is not supported by chrono. This workaround relies on `parse_rfc3339` parsing the field in order.
-	let mut time = chrono::format::Parsed::new();
-	let _ = chrono::format::parse(&mut time, &path.1, [chrono::format::Item::Fixed(chrono::format::Fixed::RFC3339)].iter());
+	let mut parsed = chrono::format::Parsed::new();
+	let _ = chrono::format::parse(&mut parsed, time, [chrono::format::Item::Fixed(chrono::format::Fixed::RFC3339)].iter());
 
 	// Seconds and milis default to max to make sure object in that exact time will match
-	time.second = time.second.or(Some(59));
-	time.nanosecond = time.nanosecond.or(Some(999_000_000));
-	let time = time.to_datetime_with_timezone(&Utc).map_err(ErrorBadRequest)?;
+	parsed.second = parsed.second.or(Some(59));
+	parsed.nanosecond = parsed.nanosecond.or(Some(999_000_000));
+	parsed.to_datetime_with_timezone(&Utc).map_err(ErrorBadRequest)
+}
+
+fn get_updated(object: &Map<String, Value>) -> Result<DateTime<Utc>, ActixError> {
+	let updated = object.get("updated").and_then(Value::as_str).ok_or_else(|| internal_error("`updated` is missing"))?;
+	Ok(DateTime::parse_from_rfc3339(updated).map_err(internal_error)?.with_timezone(&Utc))
+}
+
+fn record_url(req: &HttpRequest, id: &ObjectId, time: &DateTime<Utc>) -> Result<String, ActixError> {
+	let time = time.to_rfc3339_opts(SecondsFormat::Millis, true);
+	Ok(req.url_for("record", ["", time.as_str(), id.to_string().as_str()]).map_err(internal_error)?.to_string())
+}
 
-	let mut post = get_record(&id, &time, &db).await?.ok_or(ErrorNotFound(""))?;
+// Records are Mementos (RFC 7089) of the post
+pub async fn record(
+	req: HttpRequest, path: Path<((), String)>, id: ObjectId, requester: Requester, db: Data<Client>
+) -> Result<CustomizeResponder<Json<Map<String, Value>>>, ActixError> {
+	let links = memento_links(&req, &id)?;
+	let (post, memento_datetime) = get_memento(req, path, id, requester, db).await?;
+	Ok(Json(post).customize().insert_header(("Memento-Datetime", memento_datetime)).insert_header(("Link", links)))
+}
+
+// The revision a record URL names, along with its `Memento-Datetime`
+pub async fn get_memento(req: HttpRequest, path: Path<((), String)>, id: ObjectId, requester: Requester, db: Data<Client>) -> Result<(Map<String, Value>, String), ActixError> {
+	check_visible(&req, &get(&id, &db).await?.ok_or(ErrorNotFound(""))?, &requester, &db).await?;
+	let mut post = get_record(&id, &parse_record_time(&path.1)?, &db).await?.ok_or(ErrorNotFound(""))?;
+	let memento_datetime = http_date(get_updated(&post)?);
 	let options = json_ld_options(&req)?;
 	let context = context(&post, req.head())?;
 	post = unstrip_object(post, &options).await.map_err(internal_error)?;
-	Ok(Json(compact_object(&post, context, &options).await.map_err(internal_error)?))
+	Ok((compact_object(&post, context, &options).await.map_err(internal_error)?, memento_datetime))
+}
+
+// Each revision is listed with the URL of its record.
+pub async fn get_changelog(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, requester: Requester, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	check_visible(&req, &get(&id, &db).await?.ok_or(ErrorNotFound(""))?, &requester, &db).await?;
+	let url = Url::try_from(&req)?.to_string();
+	let cursor = match query.cursor() {
+		Some(cursor) => cursor,
+		None => return serve_collection(&req, ordered_collection(&url, count_revisions(&id, &db).await?)).await
+	};
+	let mut items = Vec::new();
+	for position in get_revisions(&id, cursor, &db).await? {
+		let updated = position.time.to_rfc3339_opts(SecondsFormat::Millis, true);
+		let record = record_url(&req, &id, &position.time)?;
+		let mut diff = req.url_for("diff", [id.to_string()]).map_err(internal_error)?;
+		diff.query_pairs_mut().append_pair("to", &updated);
+		items.push((
+			position,
+			json!(Value, {
+				"@id": record,
+				ns!(as:updated): [{ "@type": ns!(xsd:dateTime), "@value": updated }],
+				ns!(as:url): [{ "@type": [ns!(as:Link)], ns!(as:href): [{ "@id": diff.to_string() }], ns!(as:rel): [{ "@value": "diff" }] }]
+			})
+		));
+	}
+	serve_collection(&req, ordered_collection_page(&url, cursor, items)).await
 }
 
-pub async fn get_changelog(req: HttpRequest, id: ObjectId, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
-	let mut history = get(&id, &db).await?.ok_or(ErrorNotFound(""))?;
+// The diff is between the two revisions as the reader would get them from their records.
+pub async fn get_diff(req: HttpRequest, id: ObjectId, query: Query<DiffQuery>, requester: Requester, db: Data<Client>) -> Result<Json<Map<String, Value>>, ActixError> {
+	check_visible(&req, &get(&id, &db).await?.ok_or(ErrorNotFound(""))?, &requester, &db).await?;
+	let to_time = parse_record_time(&query.to)?;
+	let to = get_record(&id, &to_time, &db).await?.ok_or(ErrorNotFound(""))?;
+	let from = match &query.from {
+		Some(from) => {
+			let from_time = parse_record_time(from)?;
+			if from_time > to_time {
+				return Err(ErrorBadRequest("`from` is later than `to`"));
+			}
+			get_record(&id, &from_time, &db).await?.ok_or(ErrorNotFound(""))?
+		}
+		// The first revision is compared with nothing
+		None => get_record(&id, &(get_updated(&to)? - Duration::milliseconds(1)), &db).await?.unwrap_or_default()
+	};
+
 	let options = json_ld_options(&req)?;
-	let context = context(&history, req.head())?;
-	history = unstrip_object(history, &options).await.map_err(internal_error)?;
-	Ok(Json(compact_object(&history, context, &options).await.map_err(internal_error)?))
+	let context = context(&to, req.head())?;
+	let mut revisions = Vec::new();
+	for revision in [from, to] {
+		if revision.is_empty() {
+			revisions.push(revision);
+			continue;
+		}
+		let mut revision = compact_object(&unstrip_object(revision, &options).await.map_err(internal_error)?, context.clone(), &options)
+			.await
+			.map_err(internal_error)?;
+		revision.remove("@context");
+		revisions.push(revision);
+	}
+	Ok(Json(diff(&revisions[0], &revisions[1])))
+}
+
+pub fn memento_links(req: &HttpRequest, id: &ObjectId) -> Result<String, ActixError> {
+	let original = req.url_for("post", ["", id.to_string().as_str()]).map_err(internal_error)?;
+	let timemap = req.url_for("timemap", once(id.to_string())).map_err(internal_error)?;
+	Ok(memento::links(original.as_str(), timemap.as_str()))
+}
+
+// Redirects to the record at or before `Accept-Datetime`, or the first one if there's none that early.
+pub async fn time_gate(req: HttpRequest, id: ObjectId, requester: Requester, db: Data<Client>) -> Result<HttpResponse, ActixError> {
+	check_visible(&req, &get(&id, &db).await?.ok_or(ErrorNotFound(""))?, &requester, &db).await?;
+	let time = req
+		.headers()
+		.get("Accept-Datetime")
+		.and_then(|time| time.to_str().ok())
+		.and_then(memento::parse_accept_datetime)
+		.ok_or(ErrorBadRequest("invalid `Accept-Datetime`"))?;
+	let record = match get_record(&id, &time, &db).await? {
+		Some(record) => record,
+		None => get_first_record(&id, &db).await?.ok_or(ErrorNotFound(""))?
+	};
+	Ok(HttpResponse::Found()
+		.insert_header(("Location", record_url(&req, &id, &get_updated(&record)?)?))
+		.insert_header(("Vary", "Accept-Datetime"))
+		.insert_header(("Link", memento_links(&req, &id)?))
+		.finish())
+}
+
+// TimeMaps are paged like collections, though each page lists its Mementos oldest first.
+pub async fn timemap(req: HttpRequest, id: ObjectId, query: Query<PageQuery>, requester: Requester, db: Data<Client>) -> Result<HttpResponse, ActixError> {
+	check_visible(&req, &get(&id, &db).await?.ok_or(ErrorNotFound(""))?, &requester, &db).await?;
+	let url = Url::try_from(&req)?.to_string();
+	let cursor = query.cursor().unwrap_or(Cursor::Newest);
+	let revisions = get_revisions(&id, cursor, &db).await?;
+
+	let (mut older, mut newer) = (None, None);
+	if let (Some(newest), Some(oldest)) = (revisions.first(), revisions.last()) {
+		if revisions.len() as i64 == PAGE_SIZE || matches!(cursor, Cursor::After(_)) {
+			older = Some(page_id(&url, Cursor::Before(*oldest)));
+		}
+		if cursor != Cursor::Newest {
+			newer = Some(page_id(&url, Cursor::After(*newest)));
+		}
+	}
+	let mut mementos = Vec::new();
+	for position in revisions.iter().rev() {
+		mementos.push((record_url(&req, &id, &position.time)?, position.time));
+	}
+
+	let original = req.url_for("post", ["", id.to_string().as_str()]).map_err(internal_error)?;
+	let this = if query.cursor().is_some() { page_id(&url, cursor) } else { url.clone() };
+	Ok(HttpResponse::Ok()
+		.content_type("application/link-format")
+		.body(memento::timemap(original.as_str(), &this, &mementos, older.as_deref(), newer.as_deref())))
 }
 
 pub async fn create_account(req: HttpRequest, mut account: Json<Map<String, Value>>, db: Data<Client>) -> Result<impl Responder, ActixError> {
-	let id = generate_id().to_string();
+	let oid = generate_id();
+	let id = oid.to_string();
+	let name = account.get("name").map(|name| name.as_str().ok_or(ErrorBadRequest("`name` must be string"))).transpose()?.map(str::to_string);
 	let url = req
-		.url_for("account", [
-			&*if let Some(name) = account.get("name") {
-				Cow::Owned(name.as_str().ok_or(ErrorBadRequest("`name` must be string"))?.to_string() + "-")
-			} else {
-				Cow::Borrowed("")
-			},
-			&id
-		])
+		.url_for("account", [&*name.as_ref().map_or(Cow::Borrowed(""), |name| Cow::Owned(name.to_string() + "-")), &id])
 		.map_err(internal_error)?
 		.to_string();
 	let options = json_ld_options(&req)?;
@@ -132,151 +500,594 @@ pub async fn create_account(req: HttpRequest, mut account: Json<Map<String, Valu
 	let timestamp = datetime(Utc::now());
 	account.insert(ns!(as:published).to_string(), timestamp.clone());
 	account.insert(ns!(as:updated).to_string(), timestamp);
-	insert(&strip_object(&account, context, &options).await.map_err(ErrorBadRequest)?, &db).await?;
-	Ok("".to_string().customize().with_status(StatusCode::CREATED).insert_header(("Location", url)))
+	let (public_key, private_key) = generate_keypair().map_err(internal_error)?;
+	let account = strip_object(&account, context, &options).await.map_err(ErrorBadRequest)?;
+	if let Some(name) = &name {
+		take_name(name, &oid, &db).await?;
+	}
+	let created = async {
+		insert(&account, &db).await?;
+		insert_keypair(&oid, &public_key, &private_key, &db).await?;
+		issue_token(&oid, &db).await
+	}
+	.await;
+	let token = match (created, &name) {
+		(Ok(token), _) => token,
+		(Err(error), Some(name)) => {
+			release_name(name, &oid, &db).await?;
+			return Err(error);
+		}
+		(Err(error), None) => return Err(error)
+	};
+	Ok(HttpResponse::Created().insert_header(("Location", url)).json(json!(Value, { "access_token": token, "token_type": "Bearer" })))
+}
+
+// Accounts from before names were claimed are only found by looking
+async fn take_name(name: &str, actor: &ObjectId, db: &Client) -> Result<(), ActixError> {
+	if get_actor_by_name(name, db).await?.is_some() || !claim_name(name, actor, db).await? {
+		return Err(ErrorConflict("`name` is already taken"));
+	}
+	Ok(())
 }
 
-pub async fn submit(req: HttpRequest, ref actor: ObjectId, json: Json<Map<String, Value>>, db: Data<Client>) -> impl Responder {
+fn get_actor_name(object: &Map<String, Value>) -> Option<String> {
+	match object.get("type").and_then(Value::as_str) {
+		Some(ty) if ACTOR_TYPES.contains(&ty) => object.get("name").and_then(Value::as_str).map(str::to_string),
+		_ => None
+	}
+}
+
+// We keep just the hash of a token, so it's shown only once. A lost one can't be recovered,
+// the operator issues a new one instead with `graft issue-token <account id>`.
+pub async fn issue_token(owner: &ObjectId, db: &Client) -> Result<String, ActixError> {
+	get(owner, db).await?.ok_or(ErrorNotFound("no such account"))?;
+	let (token, hash) = generate_token().map_err(internal_error)?;
+	insert_token(owner, &hash, db).await?;
+	Ok(token)
+}
+
+// Trades the bearer token of a request for a new one, which is served the same way as from `create_account`.
+// The old token stops working, so that one that may have leaked can be rotated.
+pub async fn reissue_token(req: HttpRequest, db: Data<Client>) -> Result<impl Responder, ActixError> {
+	let old = bearer_hash(&req)?.ok_or(ErrorUnauthorized(""))?;
+	let owner = take_token(&old, &db).await?.ok_or(ErrorUnauthorized("invalid token"))?;
+	let token = issue_token(&owner, &db).await?;
+	Ok(HttpResponse::Ok().json(json!(Value, { "access_token": token, "token_type": "Bearer" })))
+}
+
+static RECORD: Lazy<ResourceDef> = Lazy::new(|| ResourceDef::new(format!("/post/{}", RECORD_PATH)));
+
+// The time in a record URL, if `iri` is one. It's matched just like the `record` route does.
+fn get_record_time(iri: &str) -> Option<DateTime<Utc>> {
+	let url = iri.parse::<Url>().ok()?;
+	if url.host_str() != Some(crate::HOST) {
+		return None;
+	}
+	let mut path = RouterPath::new(url.path());
+	if !RECORD.capture_match_info(&mut path) {
+		return None;
+	}
+	parse_record_time(path.get("time")?).ok()
+}
+
+// Properties set to null are removed, the rest replace the old ones.
+fn apply_update(old: &mut Map<String, Value>, update: Map<String, Value>) {
+	for (key, value) in update {
+		if key == "id" {
+			continue;
+		}
+		if value.is_null() {
+			old.remove(&key);
+		} else {
+			old.insert(key, value);
+		}
+	}
+}
+
+// Admins may modify anything, e.g. to take down abusive posts.
+fn can_modify(object: &Map<String, Value>, actor: &ObjectId) -> bool {
+	crate::ADMINS.contains(&actor.to_string()) || get_owners(object).contains(&actor.into())
+}
+
+pub async fn submit(req: HttpRequest, ref actor: ObjectId, requester: Requester, json: Json<Map<String, Value>>, db: Data<Client>) -> impl Responder {
+	if requester.0.is_none() {
+		return Err(ErrorUnauthorized(""));
+	} else if !requester.is(actor) {
+		return Err(ErrorForbidden("only the owner can post to an outbox"));
+	}
 	let options = json_ld_options(&req)?;
 	let context = context(&json, req.head())?;
 	let ref mut json = expand_object(&json, &options).await.map_err(ErrorBadRequest)?;
 	let timestamp = datetime(Utc::now());
+	let activity_id = generate_id();
+	let mut local_followees = Vec::new();
+
+	// Bare objects are wrapped in a Create, as C2S section 6.2.1 says
+	if get_activity_type(json)?.is_none() {
+		if is_activity(json) {
+			return Err(ErrorNotImplemented("unsupported activity type"));
+		}
+		let mut create = Map::new();
+		create.insert("@type".to_string(), json!(Value, [ns!(as:Create)]));
+		copy_recipients(json, &mut create);
+		create.insert(ns!(as:object).to_string(), Value::Array(vec![Value::Object(std::mem::take(json))]));
+		*json = create;
+	}
 
-	let ty = json
-		.get("@type")
-		.ok_or(ErrorBadRequest("missing `type`"))?
-		.as_array()
-		.expect("expanded object")
-		.iter()
-		.map(|ty| ty.as_str().expect("expanded object"))
-		.find_map(|ty| {
-			let deserializer: StrDeserializer<serde::de::value::Error> = ty.into_deserializer();
-			SupportedActivity::deserialize(deserializer).ok()
-		});
 	use SupportedActivity::*;
-	if let Some(ty) = ty {
-		match ty {
-			Create => {
-				let mut new_object = Vec::new();
-				for mut object in take_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
-					copy_recipients(json, &mut object);
-					copy_recipients(&object, json);
-					object.insert(ns!(as:attributedTo).to_string(), json!(Value, {"@id": "../of/".to_string() + &actor.to_string()}));
-					object.insert("@id".to_string(), generate_id().to_string().into()); // Overwrite any existing id
-
-					object.insert(ns!(as:published).to_string(), timestamp.clone());
-					object.insert(ns!(as:updated).to_string(), timestamp.clone());
-					insert(&strip_object(&object, context.clone(), &options).await.map_err(internal_error)?, &db).await?;
-					new_object.push(Value::Object(object));
+	let ty = get_activity_type(json)?.expect("bare objects are wrapped");
+	match ty {
+		Create => {
+			let mut new_object = Vec::new();
+			for mut object in take_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
+				copy_recipients(json, &mut object);
+				copy_recipients(&object, json);
+				let blind = take_blind_recipients(&mut object);
+				object.insert(ns!(as:attributedTo).to_string(), json!(Value, {"@id": "../of/".to_string() + &actor.to_string()}));
+				let object_id = generate_id();
+				object.insert("@id".to_string(), object_id.to_string().into()); // Overwrite any existing id
+
+				object.insert(ns!(as:published).to_string(), timestamp.clone());
+				object.insert(ns!(as:updated).to_string(), timestamp.clone());
+				let items = if is_collection(&object)? { take_items(&mut object) } else { vec![] };
+				insert(&strip_object(&object, context.clone(), &options).await.map_err(internal_error)?, &db).await?;
+				insert_blind_recipients(&object_id, &blind, &db).await?;
+				// Listed first is the newest
+				for item in items.iter().rev() {
+					insert_item(&object_id, to_reference(item), &db).await?;
 				}
-				json.insert(ns!(as:object).to_string(), Value::Array(new_object));
-			}
-			Update => {
-				for object in get_objects_mut(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
-					let mut old = get(&get_id(object)?, &db).await?.ok_or(ErrorBadRequest("`object` not found"))?;
-					// if old.get_str("attributedTo") != Ok(json["actor"].as_str().expect()) {
-					// return Err(error::ErrorBadRequest("Unauthorized edit"));
-					// }
-					object.insert(ns!(as:updated).to_string(), timestamp.clone());
-					for (key, value) in strip_object(object, context.clone(), &options).await.map_err(internal_error)? {
-						if key == "id" {
-							continue;
-						}
-						if value.is_null() {
-							old.remove(&key);
-						} else {
-							old.insert(key, value);
-						}
+				new_object.push(Value::Object(object));
+			}
+			json.insert(ns!(as:object).to_string(), Value::Array(new_object));
+		}
+		Update => {
+			// The activity goes to whoever the objects are for, blind recipients included, as with Create
+			for object in get_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))?.cloned().collect::<Vec<_>>() {
+				copy_recipients(&object, json);
+			}
+			for object in get_objects_mut(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
+				let id = get_id(object)?;
+				let mut old = get(&id, &db).await?.ok_or(ErrorBadRequest("`object` not found"))?;
+				if !can_modify(&old, actor) {
+					return Err(ErrorForbidden("`object` is not owned by `actor`"));
+				}
+				// Naming a record reverts to it, as a new revision on top of the latest
+				if let Some(time) = get_record_time(get_iri(object)?) {
+					old = get_record(&id, &time, &db).await?.ok_or(ErrorBadRequest("record not found"))?;
+				}
+				object.insert(ns!(as:updated).to_string(), timestamp.clone());
+				let blind = take_blind_recipients(object);
+				let former_name = get_actor_name(&old);
+				apply_update(&mut old, strip_object(object, context.clone(), &options).await.map_err(internal_error)?);
+				// Renaming an actor takes the new name first, and lets the former one go once it's done
+				let name = get_actor_name(&old).filter(|name| Some(name) != former_name.as_ref());
+				if let Some(name) = &name {
+					take_name(name, &id, &db).await?;
+				}
+				if let Err(error) = insert(&old.clone(), &db).await {
+					if let Some(name) = &name {
+						release_name(name, &id, &db).await?;
+					}
+					return Err(error);
+				}
+				if let (Some(former_name), true) = (&former_name, get_actor_name(&old) != former_name) {
+					release_name(former_name, &id, &db).await?;
+				}
+				insert_blind_recipients(&id, &blind, &db).await?;
+				*object = old;
+			}
+		}
+		Delete => {
+			for object in get_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
+				let id = get_id(&object)?;
+				let old = get(&id, &db).await?.ok_or(ErrorBadRequest("`object` not found"))?;
+				if !can_modify(&old, actor) {
+					return Err(ErrorForbidden("`object` is not owned by `actor`"));
+				}
+				let old = unstrip_object(old, &options).await.map_err(internal_error)?;
+				let tombstone = tombstone(&id.to_string(), &old, &timestamp);
+				insert(&strip_object(&tombstone, context.clone(), &options).await.map_err(internal_error)?, &db).await?;
+			}
+		}
+		Follow => {
+			let followees = get_iris(json, ns!(as:object)).map(|followee| followee.to_string()).collect::<Vec<_>>();
+			if followees.is_empty() {
+				return Err(ErrorBadRequest("invalid `object`"));
+			}
+			for followee in followees {
+				add_recipient(json, &followee); // Make sure the followee hears about it
+				insert_follow(actor.into(), to_reference(&followee), (&activity_id).into(), &db).await?;
+				if let Ok(followee) = followee.parse::<Url>().map_err(ErrorBadRequest).and_then(ObjectId::try_from) {
+					local_followees.push(followee);
+				}
+			}
+		}
+		Accept | Reject => {
+			for follow in get_iris(json, ns!(as:object)).map(|follow| follow.to_string()).collect::<Vec<_>>() {
+				let follower = if ty == Accept {
+					accept_follow(to_reference(&follow), actor.into(), &db).await?
+				} else {
+					reject_follow(to_reference(&follow), actor.into(), &db).await?
+				};
+				let follower = follower.ok_or(ErrorBadRequest("no such follow request"))?;
+				add_recipient(json, &from_reference(&follower, "of").ok_or_else(|| internal_error("bad follower"))?);
+			}
+		}
+		Add | Remove => {
+			let prop = if ty == Remove && json.contains_key(ns!(as:origin)) { ns!(as:origin) } else { ns!(as:target) };
+			let collections = get_iris(json, prop).map(|collection| collection.to_string()).collect::<Vec<_>>();
+			if collections.is_empty() {
+				return Err(ErrorBadRequest(if ty == Add { "invalid `target`" } else { "invalid `origin`" }));
+			}
+			let objects = get_iris(json, ns!(as:object)).map(|object| object.to_string()).collect::<Vec<_>>();
+			if objects.is_empty() {
+				return Err(ErrorBadRequest("invalid `object`"));
+			}
+			for collection in collections {
+				let id = collection.parse::<Url>().map_err(ErrorBadRequest).and_then(ObjectId::try_from)?;
+				let collection = get(&id, &db).await?.ok_or(ErrorBadRequest("collection not found"))?;
+				let collection = unstrip_object(collection, &options).await.map_err(internal_error)?;
+				if !is_collection(&collection)? {
+					return Err(ErrorBadRequest("not a collection"));
+				}
+				if !get_iris(&collection, ns!(as:attributedTo)).any(|owner| to_reference(owner) == Bson::from(actor)) {
+					return Err(ErrorForbidden("collection is not owned by `actor`"));
+				}
+				for object in &objects {
+					if ty == Add {
+						insert_item(&id, to_reference(object), &db).await?;
+					} else {
+						delete_item(&id, to_reference(object), &db).await?;
+					}
+				}
+			}
+		}
+		Like => {
+			let objects = get_iris(json, ns!(as:object)).map(|object| object.to_string()).collect::<Vec<_>>();
+			if objects.is_empty() {
+				return Err(ErrorBadRequest("invalid `object`"));
+			}
+			let (mut earlier, mut fresh) = (None, false);
+			for object in objects {
+				match insert_like(actor.into(), to_reference(&object), (&activity_id).into(), &db).await? {
+					Some(like) => earlier = earlier.or(Some(like)),
+					None => {
+						fresh = true;
+						notify_author(json, &object, &db).await;
 					}
-					insert(&old.clone(), &db).await?;
-					*object = old;
 				}
 			}
-			Delete => {
+			// Everything was liked already, so there's nothing new to do
+			if let (Some(like), false) = (earlier, fresh) {
+				let url = from_reference(&like, "activity").ok_or_else(|| internal_error("bad like"))?;
+				return Ok("".to_string().customize().with_status(StatusCode::OK).insert_header(("Location", url)));
+			}
+		}
+		Announce => {
+			let objects = get_iris(json, ns!(as:object)).map(|object| object.to_string()).collect::<Vec<_>>();
+			if objects.is_empty() {
+				return Err(ErrorBadRequest("invalid `object`"));
+			}
+			for object in objects {
+				insert_share(actor.into(), to_reference(&object), (&activity_id).into(), &db).await?;
+				notify_author(json, &object, &db).await;
+			}
+		}
+		Block => {
+			let objects = get_iris(json, ns!(as:object)).map(|object| object.to_string()).collect::<Vec<_>>();
+			if objects.is_empty() {
+				return Err(ErrorBadRequest("invalid `object`"));
+			}
+			for object in objects {
+				insert_block(actor.into(), to_reference(&object), (&activity_id).into(), &db).await?;
+			}
+			// Blocks are private, least of all the blocked actor should get one
+			for key in [ns!(as:to), ns!(as:cc), ns!(as:bto), ns!(as:bcc), ns!(as:audience)] {
+				json.remove(key);
+			}
+		}
+		Undo => {
+			let objects = get_iris(json, ns!(as:object)).map(|object| object.to_string()).collect::<Vec<_>>();
+			if objects.is_empty() {
+				return Err(ErrorBadRequest("invalid `object`"));
+			}
+			for object in objects {
+				let id = object.parse::<Url>().map_err(ErrorBadRequest).and_then(ObjectId::try_from)?;
+				let undone = get(&id, &db).await?.ok_or(ErrorBadRequest("`object` not found"))?;
+				let undone = unstrip_object(undone, &options).await.map_err(internal_error)?;
+				if !get_iris(&undone, ns!(as:actor)).any(|performer| to_reference(performer) == Bson::from(actor)) {
+					return Err(ErrorForbidden("`object` is not performed by `actor`"));
+				}
+				if !undo(&undone, actor.into(), &db).await? {
+					return Err(ErrorBadRequest("`object` can't be undone"));
+				}
+				// Whoever heard of the activity should hear of this too
+				for recipient in get_iris(&undone, ns!(as:to)).chain(get_iris(&undone, ns!(as:cc))) {
+					add_recipient(json, recipient);
+				}
+			}
+		}
+	}
+	let url = perform(&req, actor, &activity_id, std::mem::take(json), context, &options, &db).await?;
+	let follower = from_reference(&actor.into(), "of").expect("local reference");
+	for followee in local_followees {
+		auto_accept(&req, &url, &follower, &followee, &options, &db).await?;
+	}
+	Ok("".to_string().customize().with_status(StatusCode::CREATED).insert_header(("Location", url)))
+}
+
+// Authors learn about what's done to their objects from their inbox, where local ones get it from the queue like everyone else.
+// Failing to find them isn't fatal, the activity just won't reach them.
+async fn notify_author(activity: &mut Map<String, Value>, object: &str, db: &Client) {
+	if let Some(author) = resolve(object, db).await.ok().and_then(|object| object.get("attributedTo").and_then(Value::as_str).map(|author| author.to_string())) {
+		add_recipient(activity, &author);
+	}
+}
+
+// Stores an activity done by a local actor and sends it out. Returns its URL.
+async fn perform(
+	req: &HttpRequest, actor: &ObjectId, id: &ObjectId, mut activity: Map<String, Value>, context: Vec<Value>, options: &JsonLdOptions<'_, Value>, db: &Client
+) -> Result<String, ActixError> {
+	let timestamp = datetime(Utc::now());
+	activity.insert(ns!(as:published).to_string(), timestamp.clone());
+	activity.insert(ns!(as:updated).to_string(), timestamp);
+	let url = req.url_for("activity", once(id.to_string())).map_err(internal_error)?.to_string();
+	activity.insert("@id".to_string(), id.to_string().into());
+	activity.insert(ns!(as:actor).to_string(), json!(Value, { "@id": actor.to_string() }));
+	let blind = take_blind_recipients(&mut activity);
+	let activity = strip_object(&activity, context, options).await.map_err(internal_error)?;
+	insert(&activity, db).await?;
+	insert_blind_recipients(id, &blind, db).await?;
+	enqueue(&federated_object(activity, url.clone()).await.map_err(internal_error)?, blind, actor, db).await?;
+	Ok(url)
+}
+
+// Accepts a follow request right away, unless the followee wants to review them.
+async fn auto_accept(req: &HttpRequest, follow: &str, follower: &str, followee: &ObjectId, options: &JsonLdOptions<'_, Value>, db: &Client) -> Result<(), ActixError> {
+	let manual = get(followee, db).await?.and_then(|actor| actor.get("manuallyApprovesFollowers").and_then(|manual| manual.as_bool())).unwrap_or(false);
+	if manual || accept_follow(to_reference(follow), followee.into(), db).await?.is_none() {
+		return Ok(());
+	}
+	let mut accept = Map::new();
+	accept.insert("@type".to_string(), json!(Value, [ns!(as:Accept)]));
+	accept.insert(ns!(as:object).to_string(), json!(Value, [{ "@id": follow }]));
+	accept.insert(ns!(as:to).to_string(), json!(Value, [{ "@id": follower }]));
+	perform(req, followee, &generate_id(), accept, vec![CONTEXT.clone()], options, db).await.map(|_| ())
+}
+
+// Reverses the side effects of `activity`, returning `false` if it has none to reverse.
+// Relations are looked up along with `actor`, so nobody can undo others' activities.
+async fn undo(activity: &Map<String, Value>, actor: Bson, db: &Client) -> Result<bool, ActixError> {
+	let reference = to_reference(get_iri(activity)?);
+	match get_activity_type(activity)? {
+		Some(SupportedActivity::Follow) => delete_follow(reference, actor, db).await?,
+		Some(SupportedActivity::Like) => delete_like(reference, actor, db).await?,
+		Some(SupportedActivity::Block) => delete_block(reference, actor, db).await?,
+		Some(SupportedActivity::Announce) => delete_share(reference, actor, db).await?,
+		_ => return Ok(false)
+	}
+	Ok(true)
+}
+
+pub async fn delivery(req: HttpRequest, ref recipient: ObjectId, signed: Signed, db: Data<Client>) -> Result<impl Responder, ActixError> {
+	get(recipient, &db).await?.ok_or(ErrorNotFound(""))?;
+	if is_blocked(vec![recipient.into()], signed.owner.as_str().into(), &db).await? {
+		// Not telling them, so that they can't tell they're blocked
+		return Ok(HttpResponse::Accepted().finish());
+	}
+	let (id, _) = receive(&req, &signed, &db).await?;
+	deliver(recipient, id.as_str(), &db).await?;
+	Ok(HttpResponse::Accepted().finish())
+}
+
+// Servers deliver here once, instead of once for each of our users.
+pub async fn shared_delivery(req: HttpRequest, signed: Signed, db: Data<Client>) -> Result<impl Responder, ActixError> {
+	let (id, activity) = receive(&req, &signed, &db).await?;
+	for recipient in get_local_recipients(&activity, &signed.owner, &db).await? {
+		if !is_blocked(vec![(&recipient).into()], signed.owner.as_str().into(), &db).await? {
+			deliver(&recipient, id.as_str(), &db).await?;
+		}
+	}
+	Ok(HttpResponse::Accepted().finish())
+}
+
+// Our users the activity is addressed to, directly or through the followers collection of its actor
+async fn get_local_recipients(activity: &Map<String, Value>, actor: &str, db: &Client) -> Result<Vec<ObjectId>, ActixError> {
+	let followers = resolve(actor, db).await.ok().and_then(|actor| actor.get("followers").and_then(Value::as_str).map(|followers| followers.to_string()));
+	let mut recipients = Vec::new();
+	for recipient in [ns!(as:to), ns!(as:cc), ns!(as:bto), ns!(as:bcc), ns!(as:audience)].iter().flat_map(|prop| get_iris(activity, prop)) {
+		if Some(recipient) == followers.as_deref() {
+			for follower in get_local_followers(actor.into(), db).await? {
+				if let Bson::ObjectId(follower) = follower {
+					recipients.push(ObjectId::from(follower));
+				}
+			}
+		} else if let Ok(recipient) = recipient.parse::<Url>().map_err(ErrorBadRequest).and_then(ObjectId::try_from) {
+			if get(&recipient, db).await?.is_some() {
+				recipients.push(recipient);
+			}
+		}
+	}
+	recipients.sort_by_key(|recipient| recipient.to_string());
+	recipients.dedup();
+	Ok(recipients)
+}
+
+// Processes an incoming activity. Returns its id along with the expanded activity.
+async fn receive(req: &HttpRequest, signed: &Signed, db: &Client) -> Result<(String, Map<String, Value>), ActixError> {
+	let json = serde_json::from_slice::<Map<String, Value>>(&signed.body).map_err(ErrorBadRequest)?;
+	let options = json_ld_options(&req)?;
+	let context = context(&json, req.head())?;
+	let ref mut json = expand_object(&json, &options).await.map_err(ErrorBadRequest)?;
+	let timestamp = datetime(Utc::now());
+	let id = get_iri(json)?.to_string();
+	let actor = get_iris(json, ns!(as:actor)).next().ok_or(ErrorBadRequest("missing `actor`"))?.to_string();
+	// Senders should have removed them already, but we won't keep them even if they didn't
+	take_blind_recipients(json);
+	if actor != signed.owner {
+		return Err(ErrorForbidden("`actor` doesn't match the signer"));
+	}
+	// Servers only speak for their own IRIs, or they could stand in for objects of another
+	if !same_origin(&id, &actor) {
+		return Err(ErrorForbidden("`id` isn't on the same origin as `actor`"));
+	}
+	if let Some(host) = actor.parse::<Url>().ok().as_ref().and_then(|url| url.host_str()) {
+		set_host_dead(host, false, &db).await?; // It's evidently alive
+	}
+
+	// The same activity may be delivered to several of our users. Side effects only need to happen once.
+	if get_by_iri(&id, &db).await?.is_none() {
+		use SupportedActivity::*;
+		match get_activity_type(json)? {
+			Some(Create) => {
 				for object in get_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
-					let tombstone = json!(Value, {
-						"id": get_id(&object)?.to_string(),
-						"type": "Tombstone",
-						"published": timestamp.clone(),
-						"updated": timestamp.clone(),
-						"deleted": timestamp.clone()
-					});
-					insert(&strip_object(&tombstone, context.clone(), &options).await.map_err(internal_error)?, &db).await?;
+					if object.len() == 1 {
+						continue; // Only a reference, nothing to store
+					}
+					if !same_origin(get_iri(object)?, &actor) {
+						return Err(ErrorForbidden("`object` isn't on the same origin as `actor`"));
+					}
+					if get_iris(object, ns!(as:attributedTo)).any(|author| author != actor) {
+						return Err(ErrorBadRequest("`object` must be attributed to `actor`"));
+					}
+					let mut object = object.clone();
+					take_blind_recipients(&mut object);
+					if !object.contains_key(ns!(as:updated)) {
+						object.insert(ns!(as:updated).to_string(), object.get(ns!(as:published)).cloned().unwrap_or_else(|| timestamp.clone()));
+					}
+					insert(&strip_object(&object, context.clone(), &options).await.map_err(internal_error)?, &db).await?;
 				}
 			}
-			Follow => (), // Follow is processed upon acceptance
-			Add => {
-				for target in get_objects(json, ns!(as:target)).ok_or(ErrorBadRequest("invalid `target`"))? {
-					let mut target = get(&get_id(target)?, &db).await?.ok_or(ErrorBadRequest("`target` not found"))?;
-					if !is_collection(&target)? {
-						return Err(ErrorBadRequest("`target` must be a collection"));
+			Some(Update) => {
+				for object in get_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
+					let old = get_by_iri(get_iri(object)?, &db).await?.ok_or(ErrorBadRequest("`object` not found"))?;
+					let old = unstrip_object(old, &options).await.map_err(internal_error)?;
+					if !is_owned_by(&old, &actor) {
+						return Err(ErrorForbidden("`object` is not owned by `actor`"));
 					}
-					if let Some(items) = target.get_mut(ns!(as:items)).map(|items| items.as_array_mut().unwrap()) {
-						for object in get_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
-							items.push(Value::Object(object.clone()));
+					let mut object = object.clone();
+					take_blind_recipients(&mut object);
+					object.insert(ns!(as:updated).to_string(), timestamp.clone());
+					insert(&strip_object(&object, context.clone(), &options).await.map_err(internal_error)?, &db).await?;
+				}
+			}
+			Some(Delete) => {
+				for object in get_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))? {
+					let object_id = get_iri(object)?;
+					if let Some(old) = get_by_iri(object_id, &db).await? {
+						let old = unstrip_object(old, &options).await.map_err(internal_error)?;
+						if !is_owned_by(&old, &actor) {
+							return Err(ErrorForbidden("`object` is not owned by `actor`"));
 						}
-					} else {
-						todo!() // Paging
+						let tombstone = tombstone(object_id, &old, &timestamp);
+						insert(&strip_object(&tombstone, context.clone(), &options).await.map_err(internal_error)?, &db).await?;
 					}
-					target.insert(ns!(as:updated).to_string(), timestamp.clone());
-					insert(&strip_object(&target, context.clone(), &options).await.map_err(internal_error)?, &db).await?;
 				}
 			}
-			Remove => {
-				let origins = get_objects(json, ns!(as:origin))
-					.or_else(|| get_objects(json, ns!(as:target)))
-					.ok_or(ErrorBadRequest("invalid `origin`"))?;
-				for origin in origins {
-					let mut origin = get(&get_id(origin)?, &db).await?.ok_or(ErrorBadRequest("`origin` not found"))?;
-					if !is_collection(&origin)? {
-						return Err(ErrorBadRequest("`origin` must be a collection"));
-					}
-					if let Some(items) = origin.remove(ns!(as:items)).map(|items| items.into_array().unwrap()) {
-						let mut objects = get_objects(json, ns!(as:object)).ok_or(ErrorBadRequest("invalid `object`"))?.collect::<Vec<_>>();
-						origin.insert(
-							ns!(as:items).to_string(),
-							items
-								.into_iter()
-								.filter(|item| {
-									for (i, object) in objects.iter().enumerate() {
-										if item.as_object().expect("expanded object") == *object {
-											objects.remove(i);
-											return false;
-										}
-									}
-									true
-								})
-								.collect::<Value>()
-						);
-					} else {
-						todo!() // Paging
+			Some(Follow) => {
+				for followee in get_iris(json, ns!(as:object)) {
+					if let Ok(followee) = followee.parse::<Url>().map_err(ErrorBadRequest).and_then(ObjectId::try_from) {
+						if is_blocked(vec![(&followee).into()], actor.as_str().into(), &db).await? {
+							continue;
+						}
+						insert_follow(actor.as_str().into(), (&followee).into(), id.as_str().into(), &db).await?;
+						auto_accept(&req, &id, &actor, &followee, &options, &db).await?;
 					}
 				}
 			}
-			Like => {
-				todo!()
+			Some(Accept) => {
+				for follow in get_iris(json, ns!(as:object)) {
+					accept_follow(to_reference(follow), actor.as_str().into(), &db).await?;
+				}
+			}
+			Some(Reject) => {
+				for follow in get_iris(json, ns!(as:object)) {
+					reject_follow(to_reference(follow), actor.as_str().into(), &db).await?;
+				}
+			}
+			Some(Like) => {
+				for object in get_iris(json, ns!(as:object)) {
+					if let Ok(object) = object.parse::<Url>().map_err(ErrorBadRequest).and_then(ObjectId::try_from) {
+						insert_like(actor.as_str().into(), (&object).into(), id.as_str().into(), &db).await?;
+					}
+				}
 			}
-			Block => {
-				todo!()
+			Some(Announce) => {
+				for object in get_iris(json, ns!(as:object)) {
+					if let Ok(object) = object.parse::<Url>().map_err(ErrorBadRequest).and_then(ObjectId::try_from) {
+						insert_share(actor.as_str().into(), (&object).into(), id.as_str().into(), &db).await?;
+					}
+				}
 			}
-			Undo => {
-				todo!()
+			Some(Undo) => {
+				for object in get_iris(json, ns!(as:object)) {
+					if let Some(undone) = get_by_iri(object, &db).await? {
+						undo(&unstrip_object(undone, &options).await.map_err(internal_error)?, actor.as_str().into(), &db).await?;
+					}
+				}
 			}
+			// Remote actors can't modify our collections
+			Some(Add | Remove) => return Err(ErrorForbidden("")),
+			// Remote blocks are none of our business
+			Some(Block) | None => ()
+		}
+		if !json.contains_key(ns!(as:updated)) {
+			json.insert(ns!(as:updated).to_string(), json.get(ns!(as:published)).cloned().unwrap_or(timestamp));
 		}
-		json.insert(ns!(as:published).to_string(), timestamp.clone());
-		json.insert(ns!(as:updated).to_string(), timestamp);
-		let id = generate_id().to_string();
-		let url = req.url_for("activity", once(&id)).map_err(internal_error)?.to_string();
-		json.insert("@id".to_string(), id.into());
-		json.insert(ns!(as:actor).to_string(), json!(Value, { "@id": actor.to_string() }));
 		insert(&strip_object(json, context, &options).await.map_err(internal_error)?, &db).await?;
-		Ok("".to_string().customize().with_status(StatusCode::CREATED).insert_header(("Location", url)))
-	} else {
-		// let create_activity = Create::new(, json);
-		todo!()
 	}
+	Ok((id, std::mem::take(json)))
 }
 
-pub async fn delivery() -> Result<String, ActixError> {
-	todo!()
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_get_record_time() {
+		let record = format!("https://{}/post/title-2021-12-22T18:29Z-61c36e2ca0f9f2e2a4f1d3b1", crate::HOST);
+		assert_eq!(get_record_time(&record), Some("2021-12-22T18:29:59.999Z".parse().unwrap()));
+		let record = format!("https://{}/post/2021-12-22T18:29:00.123Z-61c36e2ca0f9f2e2a4f1d3b1", crate::HOST);
+		assert_eq!(get_record_time(&record), Some("2021-12-22T18:29:00.123Z".parse().unwrap()));
+		assert_eq!(get_record_time(&format!("https://{}/post/61c36e2ca0f9f2e2a4f1d3b1", crate::HOST)), None);
+		assert_eq!(get_record_time("https://example.org/post/2021-12-22T18:29:00.000Z-61c36e2ca0f9f2e2a4f1d3b1"), None);
+	}
+
+	#[test]
+	fn test_revert() {
+		let record = json!(Value, {
+			"id": "61c36e2ca0f9f2e2a4f1d3b1",
+			"type": "Note",
+			"content": "before the bad edit",
+			"published": "2021-12-22T18:00:00.000Z",
+			"updated": "2021-12-22T18:29:00.000Z"
+		});
+		// What an Update naming the record strips down to
+		let update = json!(Value, {
+			"id": format!("https://{}/post/2021-12-22T18:29:00.000Z-61c36e2ca0f9f2e2a4f1d3b1", crate::HOST),
+			"updated": "2022-01-01T00:00:00.000Z"
+		});
+		let mut reverted = record.as_object().unwrap().clone();
+		apply_update(&mut reverted, update.as_object().unwrap().clone());
+
+		let mut expected = record.as_object().unwrap().clone();
+		expected.insert("updated".to_string(), "2022-01-01T00:00:00.000Z".into());
+		assert_eq!(reverted, expected);
+	}
+
+	#[test]
+	fn test_can_modify() {
+		let (author, other, admin) = ("61c36e2ca0f9f2e2a4f1d3b1", "61c36e2ca0f9f2e2a4f1d3b2", "61c36e2ca0f9f2e2a4f1d3b3");
+		// Nothing else reads `ADMINS` in tests, so it's still to be loaded
+		std::env::set_var("ADMINS", admin);
+		let post = json!(Value, { "id": "61c36e2ca0f9f2e2a4f1d3b4", "attributedTo": author });
+		let post = post.as_object().unwrap();
+		assert!(can_modify(post, &author.parse().unwrap()));
+		assert!(!can_modify(post, &other.parse().unwrap()));
+		assert!(can_modify(post, &admin.parse().unwrap()));
+		// Actors own themselves
+		let actor = json!(Value, { "id": other, "type": "Person" });
+		assert!(can_modify(actor.as_object().unwrap(), &other.parse().unwrap()));
+		assert!(!can_modify(actor.as_object().unwrap(), &author.parse().unwrap()));
+	}
 }
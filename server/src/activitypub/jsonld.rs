@@ -12,11 +12,15 @@ use super::{get_request_type, CONTEXT, GRAFT_CONTEXT};
 use crate::util::Url;
 
 pub fn json_ld_options(req: &HttpRequest) -> Result<JsonLdOptions<'static, Value>, ActixError> {
-	Ok(JsonLdOptions {
-		base: Some(Url::try_from(req)?.to_string()),
+	Ok(json_ld_options_with_base(Url::try_from(req)?.to_string()))
+}
+
+pub fn json_ld_options_with_base(base: String) -> JsonLdOptions<'static, Value> {
+	JsonLdOptions {
+		base: Some(base),
 		expand_context: Some(JsonOrReference::Reference(Cow::Borrowed(ns!(as)))),
 		..JsonLdOptions::default()
-	})
+	}
 }
 
 pub fn insert_graft_context(context: &mut Vec<Value>) {
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Utc};
+
+use crate::util::http_date;
+
+// Memento (RFC 7089): post URLs are TimeGates for themselves, and their records are the Mementos.
+
+// `Accept-Datetime` is an HTTP date, unlike the times in record URLs.
+pub fn parse_accept_datetime(value: &str) -> Option<DateTime<Utc>> {
+	DateTime::parse_from_rfc2822(value).ok().map(|time| time.with_timezone(&Utc))
+}
+
+// `Link` header for the original and its Mementos
+pub fn links(original: &str, timemap: &str) -> String {
+	format!(r#"<{}>; rel="original timegate", <{}>; rel="timemap"; type="application/link-format""#, original, timemap)
+}
+
+// A page of a TimeMap in `application/link-format`. `mementos` are (URL, time) pairs, oldest first.
+// `older` and `newer` are the pages next to this one, if any.
+pub fn timemap(original: &str, url: &str, mementos: &[(String, DateTime<Utc>)], older: Option<&str>, newer: Option<&str>) -> String {
+	let mut links = vec![
+		format!(r#"<{}>; rel="original""#, original),
+		format!(r#"<{}>; rel="timegate""#, original),
+		format!(r#"<{}>; rel="self"; type="application/link-format""#, url)
+	];
+	if let Some(older) = older {
+		links.push(format!(r#"<{}>; rel="prev"; type="application/link-format""#, older));
+	}
+	if let Some(newer) = newer {
+		links.push(format!(r#"<{}>; rel="next"; type="application/link-format""#, newer));
+	}
+	for (i, (memento, time)) in mementos.iter().enumerate() {
+		let rel = match (i == 0 && older.is_none(), i == mementos.len() - 1 && newer.is_none()) {
+			(true, true) => "first last memento",
+			(true, false) => "first memento",
+			(false, true) => "last memento",
+			(false, false) => "memento"
+		};
+		links.push(format!(r#"<{}>; rel="{}"; datetime="{}""#, memento, rel, http_date(*time)));
+	}
+	links.join(",\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_accept_datetime() {
+		assert_eq!(parse_accept_datetime("Thu, 01 Apr 2010 00:00:00 GMT"), Some("2010-04-01T00:00:00Z".parse().unwrap()));
+		assert_eq!(parse_accept_datetime("2010-04-01T00:00:00Z"), None);
+	}
+
+	#[test]
+	fn test_links() {
+		assert_eq!(
+			links("https://example.org/post/a", "https://example.org/timemap/a"),
+			r#"<https://example.org/post/a>; rel="original timegate", <https://example.org/timemap/a>; rel="timemap"; type="application/link-format""#
+		);
+	}
+
+	#[test]
+	fn test_timemap() {
+		let time = "2021-12-22T18:29:00Z".parse::<DateTime<Utc>>().unwrap();
+		let mementos = [
+			("https://example.org/post/2021-12-22T18:29:00.000Z-a".to_string(), time),
+			("https://example.org/post/2021-12-22T18:30:00.000Z-a".to_string(), time + chrono::Duration::minutes(1))
+		];
+		assert_eq!(
+			timemap("https://example.org/post/a", "https://example.org/timemap/a", &mementos, None, None),
+			[
+				r#"<https://example.org/post/a>; rel="original","#,
+				r#"<https://example.org/post/a>; rel="timegate","#,
+				r#"<https://example.org/timemap/a>; rel="self"; type="application/link-format","#,
+				r#"<https://example.org/post/2021-12-22T18:29:00.000Z-a>; rel="first memento"; datetime="Wed, 22 Dec 2021 18:29:00 GMT","#,
+				r#"<https://example.org/post/2021-12-22T18:30:00.000Z-a>; rel="last memento"; datetime="Wed, 22 Dec 2021 18:30:00 GMT""#,
+				""
+			]
+			.join("\n")
+		);
+
+		// Neither end is on a page in the middle
+		let page = timemap("https://example.org/post/a", "https://example.org/timemap/a?page", &mementos[..1], Some("https://example.org/timemap/a?older"), Some("https://example.org/timemap/a?newer"));
+		assert!(page.contains(r#"<https://example.org/timemap/a?older>; rel="prev"; type="application/link-format""#));
+		assert!(page.contains(r#"<https://example.org/timemap/a?newer>; rel="next"; type="application/link-format""#));
+		assert!(page.contains(r#"rel="memento"; datetime="Wed, 22 Dec 2021 18:29:00 GMT""#));
+	}
+}
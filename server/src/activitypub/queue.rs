@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+use actix_web::error::Error as ActixError;
+use actix_web::rt::time::sleep;
+use chrono::{Duration, Utc};
+use futures::future::join_all;
+use futures::stream::{self, StreamExt};
+use mongodb::bson::{self, Bson};
+use mongodb::Client;
+use openssl::pkey::{PKey, Private};
+use serde_json::{Map, Value};
+
+use super::is_public;
+use super::resolve::resolve;
+use super::signature::{get_keypair, post};
+use crate::db::{delete_job, deliver, get, get_all_followers, get_due_jobs, insert_jobs, is_blocked, is_dead_host, reschedule_job, set_host_dead, Job};
+use crate::error::internal_error;
+use crate::util::{from_reference, ObjectId, Url, ORIGIN};
+
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+const BATCH_SIZE: i64 = 100;
+const HOST_CONCURRENCY: usize = 4;
+// With the backoff below, the last attempt happens about 17 hours after the first.
+const MAX_ATTEMPTS: i32 = 10;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+	Delivered,
+	Retry,
+	GiveUp
+}
+
+fn backoff(attempts: i32) -> Duration {
+	Duration::minutes(1 << attempts.clamp(0, MAX_ATTEMPTS))
+}
+
+fn get_ids(value: &Value) -> Vec<String> {
+	match value {
+		Value::String(id) => vec![id.clone()],
+		Value::Object(object) => object.get("id").and_then(Value::as_str).map(|id| id.to_string()).into_iter().collect(),
+		Value::Array(array) => array.iter().flat_map(get_ids).collect(),
+		_ => vec![]
+	}
+}
+
+fn get_recipients(activity: &Map<String, Value>) -> Vec<String> {
+	let mut seen = HashSet::new();
+	["to", "cc", "bto", "bcc", "audience"]
+		.iter()
+		.filter_map(|key| activity.get(*key))
+		.flat_map(get_ids)
+		.filter(|recipient| !is_public(recipient) && seen.insert(recipient.clone()))
+		.collect()
+}
+
+fn get_host(url: &str) -> Option<String> {
+	url.parse::<Url>().ok()?.host_str().map(|host| host.to_string())
+}
+
+// Takes an activity in the form `federated_object` produces, and its blind recipients which it no longer has.
+pub async fn enqueue(activity: &Map<String, Value>, blind: Vec<String>, actor: &ObjectId, db: &Client) -> Result<(), ActixError> {
+	let mut recipients = get_recipients(activity);
+	for recipient in blind {
+		if !is_public(&recipient) && !recipients.contains(&recipient) {
+			recipients.push(recipient);
+		}
+	}
+	if recipients.is_empty() {
+		return Ok(());
+	}
+	insert_jobs(
+		&[Job {
+			id: bson::oid::ObjectId::new(),
+			actor: *actor,
+			activity: serde_json::to_string(activity).map_err(internal_error)?,
+			inbox: None,
+			recipients,
+			attempts: 0,
+			next_attempt: Utc::now().into()
+		}],
+		db
+	)
+	.await
+}
+
+pub async fn run(db: Client) {
+	loop {
+		// Errors are logged as they happen, and the jobs stay in the queue.
+		let _ = process(&db).await;
+		sleep(POLL_INTERVAL).await;
+	}
+}
+
+async fn process(db: &Client) -> Result<(), ActixError> {
+	let mut hosts = HashMap::<_, Vec<_>>::new();
+	for job in get_due_jobs(BATCH_SIZE, db).await? {
+		match job.inbox.as_deref().and_then(get_host) {
+			Some(host) => hosts.entry(host).or_default().push(job),
+			None => {
+				let (id, attempts) = (job.id, job.attempts);
+				recover(fan_out(job, db).await, &id, attempts, db).await;
+			}
+		}
+	}
+	join_all(hosts.into_iter().map(|(host, jobs)| async move {
+		stream::iter(jobs)
+			.for_each_concurrent(HOST_CONCURRENCY, |job| {
+				let host = &host;
+				async move {
+					let (id, attempts) = (job.id, job.attempts);
+					recover(attempt(job, host, db).await, &id, attempts, db).await;
+				}
+			})
+			.await
+	}))
+	.await;
+	Ok(())
+}
+
+// A job that errored counts as a failed attempt, so that it neither holds up the others nor comes back forever.
+async fn recover(result: Result<(), ActixError>, id: &bson::oid::ObjectId, attempts: i32, db: &Client) {
+	let error = match result {
+		Ok(()) => return,
+		Err(error) => error
+	};
+	let result = if attempts + 1 < MAX_ATTEMPTS {
+		log::warn!("job {} failed, retrying later: {}", id, error);
+		reschedule_job(id, attempts + 1, Utc::now() + backoff(attempts), db).await
+	} else {
+		log::warn!("job {} failed, giving up: {}", id, error);
+		delete_job(id, db).await
+	};
+	if let Err(error) = result {
+		log::error!("couldn't update job {}: {}", id, error);
+	}
+}
+
+// Resolves recipients into inboxes. Local recipients get the activity right away.
+async fn fan_out(job: Job, db: &Client) -> Result<(), ActixError> {
+	let activity = serde_json::from_str::<Map<String, Value>>(&job.activity).map_err(internal_error)?;
+	let activity_id = activity.get("id").and_then(Value::as_str).ok_or_else(|| internal_error("queued activity has no id"))?;
+	let activity_id = match TryInto::<ObjectId>::try_into(activity_id.parse::<Url>().map_err(internal_error)?) {
+		Ok(oid) => Bson::from(&oid),
+		Err(_) => Bson::String(activity_id.to_string())
+	};
+
+	let mut recipients = Vec::with_capacity(job.recipients.len());
+	for recipient in &job.recipients {
+		match recipient.parse::<Url>() {
+			// Addressing our followers collection means each of the followers
+			Ok(url) if url.host_str() == Some(crate::HOST) && url.path().starts_with("/followers/") => {
+				match TryInto::<ObjectId>::try_into(url) {
+					Ok(followee) => recipients.extend(get_all_followers(&followee, db).await?.iter().filter_map(|follower| from_reference(follower, "of"))),
+					Err(_) => log::warn!("no such followers collection: {}", recipient)
+				}
+			}
+			_ => recipients.push(recipient.clone())
+		}
+	}
+
+	let mut inboxes = HashSet::new();
+	for recipient in &recipients {
+		let url = match recipient.parse::<Url>() {
+			Ok(url) => url,
+			Err(_) => continue
+		};
+		if url.host_str() == Some(crate::HOST) {
+			if let Ok(oid) = TryInto::<ObjectId>::try_into(url) {
+				// Dropped for blockers, just like deliveries from other servers
+				if get(&oid, db).await?.is_some() && !is_blocked(vec![(&oid).into()], (&job.actor).into(), db).await? {
+					deliver(&oid, activity_id.clone(), db).await?;
+				}
+			}
+			continue;
+		}
+		match resolve_inbox(recipient, db).await {
+			Some(inbox) => {
+				inboxes.insert(inbox);
+			}
+			None => log::warn!("couldn't find an inbox for {}", recipient)
+		}
+	}
+
+	let mut jobs = Vec::with_capacity(inboxes.len());
+	for inbox in inboxes {
+		match get_host(&inbox) {
+			Some(host) if !is_dead_host(&host, db).await? => (),
+			_ => continue
+		}
+		jobs.push(Job {
+			id: bson::oid::ObjectId::new(),
+			actor: job.actor,
+			activity: job.activity.clone(),
+			inbox: Some(inbox),
+			recipients: vec![],
+			attempts: 0,
+			next_attempt: Utc::now().into()
+		});
+	}
+	insert_jobs(&jobs, db).await?;
+	delete_job(&job.id, db).await
+}
+
+// Shared inboxes are preferred, so each server gets the activity once.
+async fn resolve_inbox(actor: &str, db: &Client) -> Option<String> {
+	let actor = resolve(actor, db).await.ok()?;
+	actor
+		.get("endpoints")
+		.and_then(|endpoints| endpoints.get("sharedInbox"))
+		.or_else(|| actor.get("inbox"))
+		.and_then(Value::as_str)
+		.map(|inbox| inbox.to_string())
+}
+
+async fn attempt(job: Job, host: &str, db: &Client) -> Result<(), ActixError> {
+	if is_dead_host(host, db).await? {
+		return delete_job(&job.id, db).await;
+	}
+	let (_, key) = get_keypair(&job.actor, db).await?;
+	let key = PKey::private_key_from_pem(key.as_bytes()).map_err(internal_error)?;
+	let key_id = format!("{}/of/{}#main-key", *ORIGIN, job.actor.to_string());
+	let inbox = job.inbox.as_deref().expect("resolved job");
+
+	match send(inbox, job.activity.clone().into_bytes(), &key_id, &key).await {
+		Outcome::Delivered => delete_job(&job.id, db).await,
+		Outcome::Retry if job.attempts + 1 < MAX_ATTEMPTS => reschedule_job(&job.id, job.attempts + 1, Utc::now() + backoff(job.attempts), db).await,
+		Outcome::Retry => {
+			log::warn!("giving up delivering to {}, marking {} as dead", inbox, host);
+			set_host_dead(host, true, db).await?;
+			delete_job(&job.id, db).await
+		}
+		Outcome::GiveUp => {
+			log::warn!("{} refused delivery", inbox);
+			delete_job(&job.id, db).await
+		}
+	}
+}
+
+async fn send(inbox: &str, body: Vec<u8>, key_id: &str, key: &PKey<Private>) -> Outcome {
+	match post(inbox, body, key_id, key).await {
+		Ok(response) if response.status().is_success() => Outcome::Delivered,
+		// Other client errors won't go away by retrying
+		Ok(response) if response.status().is_client_error() && ![408, 429].contains(&response.status().as_u16()) => Outcome::GiveUp,
+		_ => Outcome::Retry
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+	use std::thread::{self, JoinHandle};
+	use std::time::Instant;
+
+	use super::super::signature::REQUEST_TIMEOUT;
+
+	// Answers a single request with `status`, and returns the request it got.
+	fn mock_server(status: &'static str) -> (String, JoinHandle<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/for/someone", listener.local_addr().unwrap());
+		let handle = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut buf = [0; 4096];
+			let mut request = String::new();
+			loop {
+				let n = stream.read(&mut buf).unwrap();
+				request.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+				if let Some(end) = request.find("\r\n\r\n") {
+					let length = request[..end]
+						.lines()
+						.find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|length| length.trim().parse::<usize>().unwrap()))
+						.unwrap_or(0);
+					if request.len() >= end + 4 + length {
+						break;
+					}
+				}
+				if n == 0 {
+					break;
+				}
+			}
+			stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes()).unwrap();
+			request
+		});
+		(url, handle)
+	}
+
+	fn key() -> PKey<Private> {
+		PKey::private_key_from_pem(super::super::signature::generate_keypair().unwrap().1.as_bytes()).unwrap()
+	}
+
+	#[actix_web::test]
+	async fn test_send() {
+		let key = key();
+		let (url, server) = mock_server("202 Accepted");
+		assert_eq!(send(&url, b"{}".to_vec(), "https://example.org/of/a#main-key", &key).await, Outcome::Delivered);
+		let request = server.join().unwrap().to_lowercase();
+		assert!(request.starts_with("post /for/someone "));
+		assert!(request.contains("\r\nsignature: keyid=\"https://example.org/of/a#main-key\""));
+		assert!(request.contains("\r\ndigest: sha-256="));
+		assert!(request.ends_with("\r\n\r\n{}"));
+
+		let (url, server) = mock_server("503 Service Unavailable");
+		assert_eq!(send(&url, b"{}".to_vec(), "https://example.org/of/a#main-key", &key).await, Outcome::Retry);
+		server.join().unwrap();
+
+		let (url, server) = mock_server("410 Gone");
+		assert_eq!(send(&url, b"{}".to_vec(), "https://example.org/of/a#main-key", &key).await, Outcome::GiveUp);
+		server.join().unwrap();
+	}
+
+	#[actix_web::test]
+	async fn test_send_to_hanging_host() {
+		// Accepts the connection but never answers
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/for/someone", listener.local_addr().unwrap());
+		let server = thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			thread::sleep(REQUEST_TIMEOUT * 2);
+			drop(stream);
+		});
+		let started = Instant::now();
+		assert_eq!(send(&url, b"{}".to_vec(), "https://example.org/of/a#main-key", &key()).await, Outcome::Retry);
+		assert!(started.elapsed() < REQUEST_TIMEOUT * 2);
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn test_backoff() {
+		assert_eq!(backoff(0), Duration::minutes(1));
+		assert_eq!(backoff(3), Duration::minutes(8));
+		assert_eq!(backoff(MAX_ATTEMPTS + 5), backoff(MAX_ATTEMPTS));
+	}
+
+	#[test]
+	fn test_get_recipients() {
+		let activity = json!({
+			"to": ["https://www.w3.org/ns/activitystreams#Public", "https://example.org/of/a"],
+			"cc": "https://example.org/of/b",
+			"bcc": [{ "id": "https://example.org/of/c" }, "https://example.org/of/a"]
+		});
+		assert_eq!(get_recipients(activity.as_object().unwrap()), vec![
+			"https://example.org/of/a".to_string(),
+			"https://example.org/of/b".to_string(),
+			"https://example.org/of/c".to_string()
+		]);
+	}
+}
@@ -0,0 +1,50 @@
+use std::convert::TryInto;
+
+use actix_web::error::{Error as ActixError, ErrorBadGateway, ErrorNotFound};
+use mongodb::Client;
+use serde_json::{Map, Value};
+
+use super::signature::{get, read_object};
+use super::strip::federated_object;
+use crate::db::{cache_remote, get as get_local, get_cached};
+use crate::error::internal_error;
+use crate::util::{ObjectId, Url};
+
+// Dereferences an IRI into the form other servers would see, whether it's ours or not.
+// Remote objects are cached for a while, see `db::setup`.
+pub async fn resolve(iri: &str, db: &Client) -> Result<Map<String, Value>, ActixError> {
+	if let Some(object) = resolve_local(iri, db).await? {
+		return Ok(object);
+	}
+	if let Some(object) = get_cached(iri, db).await? {
+		return Ok(object);
+	}
+	fetch(iri, db).await
+}
+
+// Same as `resolve`, but always fetches remote objects anew.
+pub async fn fetch(iri: &str, db: &Client) -> Result<Map<String, Value>, ActixError> {
+	if let Some(object) = resolve_local(iri, db).await? {
+		return Ok(object);
+	}
+	let response = get(iri).await?;
+	let origin = response.url().origin();
+	let object = read_object(response).await?;
+	// Neither a redirect nor the document itself may speak for another IRI, or the cache would be poisoned with it
+	let expected = iri.parse::<Url>().map_err(ErrorNotFound)?;
+	if object.get("id").and_then(Value::as_str) != Some(iri) || origin != expected.origin() {
+		return Err(ErrorBadGateway("fetched object is not what the IRI names"));
+	}
+	cache_remote(iri, &object, db).await?;
+	Ok(object)
+}
+
+async fn resolve_local(iri: &str, db: &Client) -> Result<Option<Map<String, Value>>, ActixError> {
+	let url = iri.parse::<Url>().map_err(ErrorNotFound)?;
+	if url.host_str() != Some(crate::HOST) {
+		return Ok(None);
+	}
+	let id: ObjectId = url.try_into().map_err(|_| ErrorNotFound(""))?;
+	let object = get_local(&id, db).await?.ok_or(ErrorNotFound(""))?;
+	federated_object(object, iri.to_string()).await.map_err(internal_error).map(Some)
+}
@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use actix_web::dev::Payload;
+use actix_web::error::{Error as ActixError, ErrorBadGateway, ErrorBadRequest, ErrorForbidden, ErrorUnauthorized};
+use actix_web::web::{block, Bytes, Data};
+use actix_web::{FromRequest, HttpRequest};
+use chrono::{DateTime, Duration, Utc};
+use futures::future::LocalBoxFuture;
+use once_cell::sync::Lazy;
+use openssl::base64::{decode_block, encode_block};
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use reqwest::header::{HeaderValue, ACCEPT, CONTENT_TYPE, DATE, HOST};
+use reqwest::redirect::Policy;
+use mongodb::Client as DbClient;
+use reqwest::{Client, Method, Request, Response};
+use serde_json::{Map, Value};
+use url::{Host, Url as NativeUrl};
+
+use super::resolve::{fetch, resolve};
+use crate::db::{get as get_local, get_private_key, get_public_key, insert_missing_keypair, ACTOR_TYPES};
+use crate::error::internal_error;
+use crate::util::{http_date, ObjectId, Url};
+
+// A host that never answers mustn't hold up everything else waiting on it. Shorter in tests so they don't take long to hit.
+const CONNECT_TIMEOUT: StdDuration = StdDuration::from_secs(if cfg!(test) { 1 } else { 10 });
+pub const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(if cfg!(test) { 2 } else { 30 });
+
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+	Client::builder()
+		.user_agent(concat!("graft/", env!("CARGO_PKG_VERSION")))
+		.connect_timeout(CONNECT_TIMEOUT)
+		.timeout(REQUEST_TIMEOUT)
+		// Only the host a request started on has been checked to be public
+		.redirect(Policy::custom(|attempt| match attempt.previous().first() {
+			Some(first) if first.origin() == attempt.url().origin() && attempt.previous().len() < MAX_REDIRECTS => attempt.follow(),
+			_ => attempt.stop()
+		}))
+		.build()
+		.unwrap()
+});
+
+const MAX_REDIRECTS: usize = 10;
+// Nothing we fetch is anywhere near this big
+const MAX_RESPONSE_SIZE: usize = 1 << 20;
+
+const ACTIVITY_JSON: &str = "application/activity+json";
+const ACCEPT_ACTIVITY: &str = r#"application/activity+json, application/ld+json; profile="https://www.w3.org/ns/activitystreams""#;
+
+// How far the `Date` of a signed request may be off from our clock
+static CLOCK_SKEW: Lazy<Duration> = Lazy::new(|| Duration::hours(1));
+
+// Signatures of requests with a body seen within the allowed clock skew, to reject replays
+static SEEN: Lazy<Mutex<HashMap<String, DateTime<Utc>>>> = Lazy::new(Default::default);
+
+// Returns PEM encoded (public, private) key pair
+pub fn generate_keypair() -> Result<(String, String), ErrorStack> {
+	let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+	Ok((
+		String::from_utf8(key.public_key_to_pem()?).expect("PEM is ASCII"),
+		String::from_utf8(key.private_key_to_pem_pkcs8()?).expect("PEM is ASCII")
+	))
+}
+
+// Accounts made before they got keys get them the first time they're needed. Returns PEM encoded (public, private) key pair.
+pub async fn get_keypair(owner: &ObjectId, db: &DbClient) -> Result<(String, String), ActixError> {
+	if let (Some(public), Some(private)) = (get_public_key(owner, db).await?, get_private_key(owner, db).await?) {
+		return Ok((public, private));
+	}
+	let (public, private) = generate_keypair().map_err(internal_error)?;
+	insert_missing_keypair(owner, &public, &private, db).await?;
+	// Whoever got there first decides
+	match (get_public_key(owner, db).await?, get_private_key(owner, db).await?) {
+		(Some(public), Some(private)) => Ok((public, private)),
+		_ => Err(internal_error("keypair is incomplete"))
+	}
+}
+
+pub fn digest(body: &[u8]) -> Result<String, ErrorStack> {
+	Ok("SHA-256=".to_string() + &encode_block(&hash(MessageDigest::sha256(), body)?))
+}
+
+// See draft-cavage-http-signatures-12 section 2.3
+pub fn signing_string<'a>(method: &str, path: &str, headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+	let mut lines = vec![format!("(request-target): {} {}", method.to_lowercase(), path)];
+	lines.extend(headers.into_iter().map(|(name, value)| format!("{}: {}", name.to_lowercase(), value.trim())));
+	lines.join("\n")
+}
+
+// Adds `Date`, `Host`, `Digest` (if the request has a body) and `Signature` headers.
+pub fn sign(request: &mut Request, key_id: &str, key: &PKey<Private>) -> Result<(), ErrorStack> {
+	let host = match request.url().port() {
+		Some(port) => format!("{}:{}", request.url().host_str().unwrap_or_default(), port),
+		None => request.url().host_str().unwrap_or_default().to_string()
+	};
+	let date = http_date(Utc::now());
+	let digest = request.body().and_then(|body| body.as_bytes()).map(digest).transpose()?;
+
+	let mut headers = vec![("host", host.as_str()), ("date", date.as_str())];
+	if let Some(ref digest) = digest {
+		headers.push(("digest", digest.as_str()));
+	}
+	let path = match request.url().query() {
+		Some(query) => format!("{}?{}", request.url().path(), query),
+		None => request.url().path().to_string()
+	};
+	let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+	signer.update(signing_string(request.method().as_str(), &path, headers.iter().copied()).as_bytes())?;
+	let signature = format!(
+		r#"keyId="{}",algorithm="rsa-sha256",headers="(request-target) {}",signature="{}""#,
+		key_id,
+		headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(" "),
+		encode_block(&signer.sign_to_vec()?)
+	);
+
+	let request_headers = request.headers_mut();
+	request_headers.insert(HOST, HeaderValue::from_str(&host).expect("valid host"));
+	request_headers.insert(DATE, HeaderValue::from_str(&date).expect("valid date"));
+	if let Some(digest) = digest {
+		request_headers.insert("Digest", HeaderValue::from_str(&digest).expect("valid digest"));
+	}
+	request_headers.insert("Signature", HeaderValue::from_str(&signature).expect("valid signature"));
+	Ok(())
+}
+
+// POST an activity to an inbox, signed on behalf of the key owner.
+pub async fn post(url: &str, body: Vec<u8>, key_id: &str, key: &PKey<Private>) -> Result<Response, ActixError> {
+	let mut request = CLIENT
+		.request(Method::POST, url)
+		.header(CONTENT_TYPE, ACTIVITY_JSON)
+		.header(ACCEPT, ACTIVITY_JSON)
+		.body(body)
+		.build()
+		.map_err(internal_error)?;
+	sign(&mut request, key_id, key).map_err(internal_error)?;
+	CLIENT.execute(request).await.map_err(internal_error)
+}
+
+// Anyone can have us fetch a URL, e.g. by signing with it as `keyId`. Hosts only we can reach must stay out of their reach.
+pub async fn get(url: &str) -> Result<Response, ActixError> {
+	let url = url.parse::<NativeUrl>().map_err(ErrorBadRequest)?;
+	if !is_public_host(&url).await? {
+		return Err(ErrorForbidden("host is not public"));
+	}
+	CLIENT.get(url).header(ACCEPT, ACCEPT_ACTIVITY).send().await.map_err(internal_error)?.error_for_status().map_err(internal_error)
+}
+
+// Reads a JSON object from a response, giving up on ones too large
+pub async fn read_object(mut response: Response) -> Result<Map<String, Value>, ActixError> {
+	if response.content_length().map_or(false, |length| length > MAX_RESPONSE_SIZE as u64) {
+		return Err(ErrorBadGateway("response is too large"));
+	}
+	let mut body = Vec::new();
+	while let Some(chunk) = response.chunk().await.map_err(ErrorBadGateway)? {
+		if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+			return Err(ErrorBadGateway("response is too large"));
+		}
+		body.extend_from_slice(&chunk);
+	}
+	serde_json::from_slice(&body).map_err(ErrorBadGateway)
+}
+
+async fn is_public_host(url: &NativeUrl) -> Result<bool, ActixError> {
+	let addresses = match url.host() {
+		Some(Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+		Some(Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+		Some(Host::Domain(domain)) => {
+			let host = (domain.to_string(), url.port_or_known_default().ok_or(ErrorBadRequest("unknown port"))?);
+			block(move || host.to_socket_addrs().map(|addresses| addresses.map(|address| address.ip()).collect::<Vec<_>>()))
+				.await
+				.map_err(internal_error)?
+				.map_err(ErrorBadGateway)?
+		}
+		None => return Ok(false)
+	};
+	Ok(!addresses.is_empty() && addresses.into_iter().all(is_public_ip))
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+	match ip {
+		IpAddr::V4(ip) => {
+			let [a, b, ..] = ip.octets();
+			// 0.0.0.0/8 and the shared address space, 100.64.0.0/10, are not covered by the methods
+			!(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast() || ip.is_documentation() || a == 0 || (a == 100 && b & 0xc0 == 64))
+		}
+		IpAddr::V6(ip) => {
+			let first = ip.segments()[0];
+			// Unique local, fc00::/7, and link local, fe80::/10
+			!(ip.is_loopback() || ip.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+				&& ip.to_ipv4().map_or(true, |ip| is_public_ip(IpAddr::V4(ip)))
+		}
+	}
+}
+
+// A request body with a valid signature, along with the owner of the key which signed it.
+pub struct Signed {
+	pub owner: String,
+	pub body: Bytes
+}
+
+impl FromRequest for Signed {
+	type Error = ActixError;
+	type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+	fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+		let req = req.clone();
+		let body = Bytes::from_request(&req, payload);
+		Box::pin(async move {
+			let body = body.await?;
+			let db = req.app_data::<Data<DbClient>>().expect("database is registered");
+			let owner = verify(&req, Some(&body[..]), db).await?;
+			Ok(Signed { owner, body })
+		})
+	}
+}
+
+// Parses `Signature` header parameters, e.g. `keyId="...",signature="..."`.
+fn parse_signature(header: &str) -> Result<HashMap<&str, &str>, ActixError> {
+	let mut params = HashMap::new();
+	let mut rest = header.trim();
+	while !rest.is_empty() {
+		let eq = rest.find('=').ok_or(ErrorBadRequest("malformed `Signature`"))?;
+		let name = rest[..eq].trim();
+		rest = rest[(eq + 1)..].trim_start();
+		let value = if let Some(quoted) = rest.strip_prefix('"') {
+			let end = quoted.find('"').ok_or(ErrorBadRequest("malformed `Signature`"))?;
+			rest = &quoted[(end + 1)..];
+			&quoted[..end]
+		} else {
+			let end = rest.find(',').unwrap_or(rest.len());
+			let value = rest[..end].trim();
+			rest = &rest[end..];
+			value
+		};
+		params.insert(name, value);
+		rest = rest.trim_start().trim_start_matches(',').trim_start();
+	}
+	Ok(params)
+}
+
+// Returns the owner of the key used to sign the request.
+pub async fn verify(req: &HttpRequest, body: Option<&[u8]>, db: &DbClient) -> Result<String, ActixError> {
+	let header = req.headers().get("Signature").ok_or(ErrorUnauthorized("missing `Signature`"))?.to_str().map_err(ErrorBadRequest)?;
+	let params = parse_signature(header)?;
+	let key_id = *params.get("keyId").ok_or(ErrorBadRequest("missing `keyId`"))?;
+	let signature = decode_block(params.get("signature").ok_or(ErrorBadRequest("missing `signature`"))?).map_err(ErrorBadRequest)?;
+	let headers = params.get("headers").copied().unwrap_or("date").split_whitespace().map(|name| name.to_lowercase()).collect::<Vec<_>>();
+
+	for required in ["(request-target)", "host", "date"].iter().chain(body.map(|_| &"digest")) {
+		if !headers.iter().any(|name| name == required) {
+			return Err(ErrorUnauthorized(format!("`{}` must be signed", required)));
+		}
+	}
+
+	let date = req.headers().get("Date").ok_or(ErrorBadRequest("missing `Date`"))?.to_str().map_err(ErrorBadRequest)?;
+	let date = DateTime::parse_from_rfc2822(date).map_err(ErrorBadRequest)?.with_timezone(&Utc);
+	let now = Utc::now();
+	if date > now + *CLOCK_SKEW || date < now - *CLOCK_SKEW {
+		return Err(ErrorUnauthorized("`Date` is too far from current time"));
+	}
+
+	if let Some(body) = body {
+		let expected = digest(body).map_err(internal_error)?;
+		let digests = req.headers().get("Digest").ok_or(ErrorBadRequest("missing `Digest`"))?.to_str().map_err(ErrorBadRequest)?;
+		// `Digest` may list several algorithms, but we only check SHA-256
+		if !digests.split(',').map(str::trim).any(|digest| digest.starts_with("SHA-256=") && digest == expected) {
+			return Err(ErrorUnauthorized("`Digest` doesn't match the body"));
+		}
+	}
+
+	if headers[0] != "(request-target)" {
+		return Err(ErrorBadRequest("`(request-target)` must come first"));
+	}
+	let mut values = Vec::with_capacity(headers.len());
+	for name in &headers[1..] {
+		if name == "(request-target)" {
+			return Err(ErrorBadRequest("`(request-target)` must come first"));
+		}
+		let value = req
+			.headers()
+			.get_all(name.as_str())
+			.map(|value| value.to_str().map(str::trim))
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(ErrorBadRequest)?;
+		if value.is_empty() {
+			return Err(ErrorBadRequest(format!("missing `{}`", name)));
+		}
+		values.push((name.as_str(), value.join(", ")));
+	}
+	let path = req.uri().path_and_query().map_or(req.path(), |path| path.as_str());
+	let string = signing_string(req.method().as_str(), path, values.iter().map(|(name, value)| (*name, value.as_str())));
+
+	let mut key = fetch_key(key_id, false, db).await?;
+	if !verify_with(&key.1, &string, &signature)? {
+		// The key may have been rotated since we cached it
+		key = fetch_key(key_id, true, db).await?;
+		if !verify_with(&key.1, &string, &signature)? {
+			return Err(ErrorUnauthorized("invalid signature"));
+		}
+	}
+
+	// Signatures are deterministic, so reading the same URL twice within a second looks the same both times.
+	// That's harmless, unlike delivering an activity again.
+	if body.is_some() {
+		let mut seen = SEEN.lock().unwrap();
+		seen.retain(|_, date| *date + *CLOCK_SKEW > now);
+		if seen.insert(encode_block(&signature), date).is_some() {
+			return Err(ErrorUnauthorized("replayed request"));
+		}
+	}
+	Ok(key.0)
+}
+
+fn verify_with(pem: &str, string: &str, signature: &[u8]) -> Result<bool, ActixError> {
+	let key = PKey::public_key_from_pem(pem.as_bytes()).map_err(ErrorUnauthorized)?;
+	let mut verifier = Verifier::new(MessageDigest::sha256(), &key).map_err(internal_error)?;
+	verifier.update(string.as_bytes()).map_err(internal_error)?;
+	Ok(verifier.verify(signature).unwrap_or(false))
+}
+
+// Returns (owner, PEM) of the key.
+async fn fetch_key(key_id: &str, refresh: bool, db: &DbClient) -> Result<(String, String), ActixError> {
+	let key_url = key_id.parse::<Url>().map_err(ErrorUnauthorized)?;
+	// Keys of our own actors are only the ones we generated, whatever other servers say
+	if key_url.host_str() == Some(crate::HOST) {
+		return local_key(key_id, db).await;
+	}
+
+	// Key ids are usually a fragment of the actor document
+	let url = key_id.split('#').next().unwrap();
+	let document = if refresh { fetch(url, db).await } else { resolve(url, db).await }.map_err(ErrorUnauthorized)?;
+	let (owner, pem) = find_key(&document, url, key_id).ok_or(ErrorUnauthorized("key not found"))?;
+	// A standalone key can claim any owner, so the owner has to list it too
+	if document.contains_key("publicKeyPem") {
+		let owner_document = if refresh { fetch(&owner, db).await } else { resolve(&owner, db).await }.map_err(ErrorUnauthorized)?;
+		if !lists_key(&owner_document, &owner, key_id) {
+			return Err(ErrorUnauthorized("key is not listed by its owner"));
+		}
+	}
+	Ok((owner, pem))
+}
+
+async fn local_key(key_id: &str, db: &DbClient) -> Result<(String, String), ActixError> {
+	let owner = key_id.strip_suffix("#main-key").ok_or(ErrorUnauthorized("key not found"))?;
+	let oid = owner.parse::<Url>().ok().and_then(|url| ObjectId::try_from(url).ok()).ok_or(ErrorUnauthorized("key not found"))?;
+	// Keys are made on demand, but only for actors
+	let actor = get_local(&oid, db).await?.ok_or(ErrorUnauthorized("key not found"))?;
+	if !actor.get("type").and_then(Value::as_str).map_or(false, |ty| ACTOR_TYPES.contains(&ty)) {
+		return Err(ErrorUnauthorized("key not found"));
+	}
+	let (pem, _) = get_keypair(&oid, db).await?;
+	Ok((owner.to_string(), pem))
+}
+
+pub fn same_origin(a: &str, b: &str) -> bool {
+	match (NativeUrl::parse(a), NativeUrl::parse(b)) {
+		(Ok(a), Ok(b)) => a.origin() == b.origin(),
+		_ => false
+	}
+}
+
+fn get_str<'a>(object: &'a Map<String, Value>, key: &str) -> Option<&'a str> {
+	object.get(key).and_then(Value::as_str)
+}
+
+// `document` is what `url` dereferenced to, either the key itself or its owner with `publicKey`.
+fn find_key(document: &Map<String, Value>, url: &str, key_id: &str) -> Option<(String, String)> {
+	if get_str(document, "id") != Some(url) {
+		return None;
+	}
+	let key = if document.contains_key("publicKeyPem") {
+		document
+	} else {
+		match document.get("publicKey")? {
+			Value::Array(keys) => keys.iter().filter_map(Value::as_object).find(|key| get_str(key, "id") == Some(key_id))?,
+			key => key.as_object()?
+		}
+	};
+	if get_str(key, "id") != Some(key_id) {
+		return None;
+	}
+	let owner = get_str(key, "owner")?;
+	if !same_origin(owner, key_id) {
+		return None;
+	}
+	// A key can only speak for the actor that lists it
+	if !document.contains_key("publicKeyPem") && get_str(document, "id") != Some(owner) {
+		return None;
+	}
+	Some((owner.to_string(), get_str(key, "publicKeyPem")?.to_string()))
+}
+
+fn lists_key(owner_document: &Map<String, Value>, owner: &str, key_id: &str) -> bool {
+	let lists = |key: &Value| key.as_str().or_else(|| key.as_object().and_then(|key| get_str(key, "id"))) == Some(key_id);
+	get_str(owner_document, "id") == Some(owner)
+		&& match owner_document.get("publicKey") {
+			Some(Value::Array(keys)) => keys.iter().any(lists),
+			Some(key) => lists(key),
+			None => false
+		}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn test_digest() {
+		assert_eq!(digest(b"").unwrap(), "SHA-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+	}
+
+	#[test]
+	fn test_signing_string() {
+		assert_eq!(
+			signing_string("POST", "/for/abc", vec![("Host", "example.org"), ("Date", "Tue, 07 Jun 2014 20:51:35 GMT")]),
+			"(request-target): post /for/abc\nhost: example.org\ndate: Tue, 07 Jun 2014 20:51:35 GMT"
+		);
+	}
+
+	#[test]
+	fn test_sign() {
+		let (public, private) = generate_keypair().unwrap();
+		let private = PKey::private_key_from_pem(private.as_bytes()).unwrap();
+		let public = PKey::public_key_from_pem(public.as_bytes()).unwrap();
+		let mut request = CLIENT.post("https://example.org/for/abc").body("{}").build().unwrap();
+		sign(&mut request, "https://example.com/of/me#main-key", &private).unwrap();
+
+		let headers = request.headers();
+		assert_eq!(headers["host"], "example.org");
+		assert_eq!(headers["digest"], digest(b"{}").unwrap().as_str());
+		let signature = headers["signature"].to_str().unwrap();
+		assert!(signature.starts_with(r#"keyId="https://example.com/of/me#main-key",algorithm="rsa-sha256",headers="(request-target) host date digest""#));
+
+		let value = signature.rsplit("signature=\"").next().unwrap().trim_end_matches('"');
+		let string = signing_string("POST", "/for/abc", vec![
+			("host", "example.org"),
+			("date", headers["date"].to_str().unwrap()),
+			("digest", headers["digest"].to_str().unwrap())
+		]);
+		let mut verifier = Verifier::new(MessageDigest::sha256(), &public).unwrap();
+		verifier.update(string.as_bytes()).unwrap();
+		assert!(verifier.verify(&decode_block(value).unwrap()).unwrap());
+	}
+
+	#[test]
+	fn test_parse_signature() {
+		let params = parse_signature(r#"keyId="https://example.org/of/a#main-key",algorithm="rsa-sha256", headers="(request-target) host date",signature="YWJj""#).unwrap();
+		assert_eq!(params["keyId"], "https://example.org/of/a#main-key");
+		assert_eq!(params["algorithm"], "rsa-sha256");
+		assert_eq!(params["headers"], "(request-target) host date");
+		assert_eq!(params["signature"], "YWJj");
+	}
+
+	#[test]
+	fn test_find_key() {
+		let actor = json!({
+			"id": "https://example.org/of/a",
+			"publicKey": {
+				"id": "https://example.org/of/a#main-key",
+				"owner": "https://example.org/of/a",
+				"publicKeyPem": "PEM"
+			}
+		});
+		let actor = actor.as_object().unwrap();
+		assert_eq!(
+			find_key(actor, "https://example.org/of/a", "https://example.org/of/a#main-key"),
+			Some(("https://example.org/of/a".to_string(), "PEM".to_string()))
+		);
+		assert_eq!(find_key(actor, "https://example.org/of/a", "https://example.org/of/a#other-key"), None);
+		// Served from somewhere else than its id says
+		assert_eq!(find_key(actor, "https://evil.example/actor", "https://evil.example/actor#main-key"), None);
+
+		let impostor = json!({
+			"id": "https://example.org/of/b",
+			"publicKey": {
+				"id": "https://example.org/of/b#main-key",
+				"owner": "https://example.org/of/a",
+				"publicKeyPem": "PEM"
+			}
+		});
+		assert_eq!(find_key(impostor.as_object().unwrap(), "https://example.org/of/b", "https://example.org/of/b#main-key"), None);
+	}
+
+	#[test]
+	fn test_find_standalone_key() {
+		let key = json!({
+			"id": "https://example.org/key",
+			"owner": "https://example.org/of/a",
+			"publicKeyPem": "PEM"
+		});
+		assert_eq!(
+			find_key(key.as_object().unwrap(), "https://example.org/key", "https://example.org/key"),
+			Some(("https://example.org/of/a".to_string(), "PEM".to_string()))
+		);
+
+		// Claiming an owner on another server
+		let forged = json!({
+			"id": "https://evil.example/key",
+			"owner": "https://example.org/of/a",
+			"publicKeyPem": "PEM"
+		});
+		assert_eq!(find_key(forged.as_object().unwrap(), "https://evil.example/key", "https://evil.example/key"), None);
+
+		let owner = json!({ "id": "https://example.org/of/a", "publicKey": "https://example.org/key" });
+		assert!(lists_key(owner.as_object().unwrap(), "https://example.org/of/a", "https://example.org/key"));
+		assert!(!lists_key(owner.as_object().unwrap(), "https://example.org/of/a", "https://example.org/other-key"));
+		let owner = json!({ "id": "https://example.org/of/b", "publicKey": [{ "id": "https://example.org/key" }] });
+		assert!(!lists_key(owner.as_object().unwrap(), "https://example.org/of/a", "https://example.org/key"));
+	}
+
+	#[test]
+	fn test_is_public_ip() {
+		for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+			assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+		}
+		for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+			assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+		}
+	}
+
+	#[actix_web::test]
+	async fn test_get_refuses_private_hosts() {
+		assert!(get("http://127.0.0.1/of/a").await.is_err());
+		assert!(get("http://[::1]/of/a").await.is_err());
+	}
+}
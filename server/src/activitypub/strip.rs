@@ -1,6 +1,7 @@
 use json_ld_rs::error::JsonLdError;
 use json_ld_rs::JsonLdOptions;
 use serde_json::{json, Map, Value};
+use url::Url;
 
 use super::jsonld::*;
 use super::{CONTEXT, GRAFT_CONTEXT};
@@ -27,8 +28,19 @@ pub async fn unstrip_object(mut object: Map<String, Value>, options: &JsonLdOpti
 	expand_object(&object, options).await
 }
 
-pub async fn unstrip_actor(object: Map<String, Value>, options: &JsonLdOptions<'_, Value>) -> Result<Map<String, Value>, JsonLdError> {
+pub async fn unstrip_actor(object: Map<String, Value>, public_key: Option<String>, options: &JsonLdOptions<'_, Value>) -> Result<Map<String, Value>, JsonLdError> {
 	let mut object = unstrip_object(object, options).await?;
+	if let Some(pem) = public_key {
+		let id = object.get("@id").and_then(|id| id.as_str()).expect("Objects must have an id").to_string();
+		object.insert(
+			ns!(sec:publicKey).to_string(),
+			json!([{
+				"@id": id.clone() + "#main-key",
+				ns!(sec:owner): [{ "@id": id }],
+				ns!(sec:publicKeyPem): [{ "@value": pem }]
+			}])
+		);
+	}
 	if let Some(oid) = get_oid(object.get("@id").and_then(|id| id.as_str()).expect("Objects must have an id")).map(|oid| oid.to_string()) {
 		if !object.contains_key("inbox") {
 			object.insert("inbox".to_string(), ("../for/".to_string() + &oid).into());
@@ -36,10 +48,26 @@ pub async fn unstrip_actor(object: Map<String, Value>, options: &JsonLdOptions<'
 		if !object.contains_key("outbox") {
 			object.insert("outbox".to_string(), ("../by/".to_string() + &oid).into());
 		}
+		let id = Url::parse(object["@id"].as_str().unwrap()).expect("expanded id is absolute");
+		for (prop, path) in [(ns!(as:followers), "../followers/"), (ns!(as:following), "../following/"), (ns!(as:liked), "../liked/")] {
+			if !object.contains_key(prop) {
+				object.insert(prop.to_string(), json!([{ "@id": id.join(&(path.to_string() + &oid)).expect("valid URL").to_string() }]));
+			}
+		}
+		if !object.contains_key(ns!(as:endpoints)) {
+			object.insert(ns!(as:endpoints).to_string(), json!([{ ns!(as:sharedInbox): [{ "@id": id.join("../inbox").expect("valid URL").to_string() }] }]));
+		}
 	}
 	Ok(object)
 }
 
+// The form other servers get: absolute IRIs with the plain ActivityStreams context.
+pub async fn federated_object(object: Map<String, Value>, url: String) -> Result<Map<String, Value>, JsonLdError> {
+	let options = json_ld_options_with_base(url);
+	let object = unstrip_object(object, &options).await?;
+	compact_object(&object, vec![CONTEXT.clone()], &JsonLdOptions { base: None, ..options }).await
+}
+
 pub async fn strip_object(object: &Map<String, Value>, mut context: Vec<Value>, options: &JsonLdOptions<'_, Value>) -> Result<Map<String, Value>, JsonLdError> {
 	insert_graft_context(&mut context);
 	let mut object = compact_object(object, context, options).await?;
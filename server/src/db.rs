@@ -1,16 +1,60 @@
+use std::time::Duration;
+
 use actix_web::error::Error as ActixError;
 use chrono::{DateTime, SecondsFormat, Utc};
 use futures::{StreamExt, TryStreamExt};
 use mongodb::bson::{self, doc, from_bson, to_bson, Bson, Document};
-use mongodb::options::{FindOneOptions, FindOptions, InsertOneOptions};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{
+	AggregateOptions, CountOptions, CreateIndexOptions, DeleteOptions, DistinctOptions, FindOneAndDeleteOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions,
+	IndexOptions, InsertManyOptions, InsertOneOptions, ReplaceOptions, UpdateOptions
+};
 use mongodb::results::InsertOneResult;
-use mongodb::Client;
+use mongodb::{Client, IndexModel};
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
 use crate::error::internal_error;
 use crate::util::ObjectId;
 use crate::DB_NAME;
 
+// How long fetched remote objects are kept
+const CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub const ACTOR_TYPES: [&str; 5] = ["Application", "Group", "Organization", "Person", "Service"];
+
+pub async fn setup(db: &Client) -> Result<(), mongodb::error::Error> {
+	let db = db.database(&DB_NAME);
+	db.collection::<Document>("objects")
+		.create_index(IndexModel::builder().keys(doc! { "name": 1 }).build(), CreateIndexOptions::default())
+		.await?;
+	db.collection::<Document>("objects")
+		.create_index(IndexModel::builder().keys(doc! { "actor": 1, "_id.t": -1, "_id.id": -1 }).build(), CreateIndexOptions::default())
+		.await?;
+	db.collection::<Document>("names")
+		.create_index(
+			IndexModel::builder().keys(doc! { "name": 1 }).options(IndexOptions::builder().unique(true).build()).build(),
+			CreateIndexOptions::default()
+		)
+		.await?;
+	db.collection::<Document>("likes")
+		.create_index(
+			IndexModel::builder().keys(doc! { "actor": 1, "object": 1 }).options(IndexOptions::builder().unique(true).build()).build(),
+			CreateIndexOptions::default()
+		)
+		.await?;
+	db.collection::<Document>("remote")
+		.create_index(
+			IndexModel::builder()
+				.keys(doc! { "fetched": 1 })
+				.options(IndexOptions::builder().expire_after(Some(CACHE_TTL)).build())
+				.build(),
+			CreateIndexOptions::default()
+		)
+		.await
+		.map(|_| ())
+}
+
 pub async fn insert(doc: &Map<String, Value>, db: &Client) -> Result<InsertOneResult, ActixError> {
 	db.database(&DB_NAME)
 		.collection("objects")
@@ -32,38 +76,697 @@ async fn get_with_query(db: &Client, query: Document) -> Result<Option<Map<Strin
 		.flatten()
 }
 
-async fn get_all_with_query(db: &Client, query: Document) -> Result<Vec<Map<String, Value>>, ActixError> {
-	db.database(&DB_NAME)
-		.collection("objects")
-		.find(
-			query,
-			FindOptions::builder().sort(Some(doc! { "_id.t": 1 })).build()
-		)
+// The latest revisions of objects that match `query` as they are now. Deleted ones are left out.
+async fn get_latest_with_query(db: &Client, query: Document) -> Result<Vec<Map<String, Value>>, ActixError> {
+	let collection = db.database(&DB_NAME).collection::<Document>("objects");
+	// Any revision may have matched, even if the latest no longer does
+	let ids = collection.distinct("_id.id", query.clone(), DistinctOptions::default()).await.map_err(internal_error)?;
+	let pipeline = vec![
+		doc! { "$match": { "_id.id": { "$in": ids } } },
+		doc! { "$sort": { "_id.t": -1 } },
+		doc! { "$group": { "_id": "$_id.id", "latest": { "$first": "$$ROOT" } } },
+		doc! { "$replaceRoot": { "newRoot": "$latest" } },
+		doc! { "$match": { "$and": [query, { "type": { "$ne": "Tombstone" } }] } },
+		doc! { "$sort": { "_id.t": 1 } }
+	];
+	collection
+		.aggregate(pipeline, AggregateOptions::default())
 		.await
-		.map_err(internal_error)? // Error from find query
-		.map_err(internal_error) // Error from returned stream
-		.map_ok(|doc| from_db_object(doc))
+		.map_err(internal_error)?
+		.map_err(internal_error)
+		.map_ok(from_db_object)
 		.map(|result| result.flatten())
-		.try_collect().await
+		.try_collect()
+		.await
 }
 
 pub async fn get(id: &ObjectId, db: &Client) -> Result<Option<Map<String, Value>>, ActixError> {
 	get_with_query(db, doc! { "_id.id": id }).await
 }
 
+pub async fn get_by_iri(iri: &str, db: &Client) -> Result<Option<Map<String, Value>>, ActixError> {
+	get_with_query(db, doc! { "_id.id": iri }).await
+}
+
+pub async fn get_actor_by_name(name: &str, db: &Client) -> Result<Option<Map<String, Value>>, ActixError> {
+	let actor = match get_with_query(db, doc! { "name": name, "type": { "$in": ACTOR_TYPES.to_vec() } }).await? {
+		Some(actor) => actor,
+		None => return Ok(None)
+	};
+	// The name may belong to an older revision
+	let id = actor["id"].as_str().expect("id is converted to string").parse::<ObjectId>().map_err(internal_error)?;
+	Ok(get(&id, db).await?.filter(|actor| actor.get("name").and_then(|actor_name| actor_name.as_str()) == Some(name)))
+}
+
+// Each name points to a single account. Returns false if another one has it already.
+pub async fn claim_name(name: &str, actor: &ObjectId, db: &Client) -> Result<bool, ActixError> {
+	let claim = db
+		.database(&DB_NAME)
+		.collection::<Document>("names")
+		.find_one_and_update(
+			doc! { "name": name },
+			doc! { "$setOnInsert": { "actor": actor, "t": Utc::now() } },
+			FindOneAndUpdateOptions::builder().upsert(true).build()
+		)
+		.await;
+	match claim {
+		Ok(claim) => Ok(claim.map_or(true, |claim| claim.get("actor") == Some(&actor.into()))),
+		// Someone else upserted the same name at the same time
+		Err(error) if is_duplicate_key(&error) => Ok(false),
+		Err(error) => Err(internal_error(error))
+	}
+}
+
+pub async fn release_name(name: &str, actor: &ObjectId, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("names")
+		.delete_one(doc! { "name": name, "actor": actor }, DeleteOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+	const DUPLICATE_KEY: i32 = 11000;
+	match &*error.kind {
+		ErrorKind::Command(error) => error.code == DUPLICATE_KEY,
+		ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == DUPLICATE_KEY,
+		_ => false
+	}
+}
+
+async fn count_distinct(field: &str, filter: Document, db: &Client) -> Result<usize, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("objects")
+		.distinct(field, filter, DistinctOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|values| values.len())
+}
+
+pub async fn count_local_actors(db: &Client) -> Result<usize, ActixError> {
+	count_distinct("_id.id", doc! { "_id.id": { "$type": "objectId" }, "type": { "$in": ACTOR_TYPES.to_vec() } }, db).await
+}
+
+// Local actors who did anything since `since`
+pub async fn count_active_actors(since: DateTime<Utc>, db: &Client) -> Result<usize, ActixError> {
+	count_distinct("actor", doc! { "actor": { "$type": "objectId" }, "published": { "$gte": since } }, db).await
+}
+
+pub async fn get_outbox(actor: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Map<String, Value>)>, ActixError> {
+	get_page("objects", doc! { "actor": actor }, "_id.t", "_id.id", cursor, db)
+		.await?
+		.into_iter()
+		.map(|(position, doc)| Ok((position, from_db_object(doc)?)))
+		.collect()
+}
+
+// With `audience`, only activities addressed to any of it count
+pub async fn count_outbox(actor: &ObjectId, audience: Option<Vec<&str>>, db: &Client) -> Result<u64, ActixError> {
+	let query = match audience {
+		Some(audience) => doc! { "actor": actor, "$or": [{ "to": { "$in": audience.clone() } }, { "cc": { "$in": audience.clone() } }, { "audience": { "$in": audience } }] },
+		None => doc! { "actor": actor }
+	};
+	count("objects", query, db).await
+}
+
+// Only the latest revision counts, so deleted posts don't, even though their older revisions are still there.
+pub async fn count_local_posts(db: &Client) -> Result<usize, ActixError> {
+	let pipeline = vec![
+		doc! { "$match": { "_id.id": { "$type": "objectId" } } },
+		doc! { "$sort": { "_id.t": -1 } },
+		doc! { "$group": { "_id": "$_id.id", "type": { "$first": "$type" }, "attributedTo": { "$first": "$attributedTo" } } },
+		doc! { "$match": { "type": { "$ne": "Tombstone" }, "attributedTo": { "$type": "objectId" } } },
+		doc! { "$count": "posts" }
+	];
+	let result = db
+		.database(&DB_NAME)
+		.collection::<Document>("objects")
+		.aggregate(pipeline, AggregateOptions::default())
+		.await
+		.map_err(internal_error)?
+		.try_next()
+		.await
+		.map_err(internal_error)?;
+	// No document at all if there are no posts
+	Ok(result.and_then(|result| result.get("posts").and_then(|posts| posts.as_i32().map(i64::from).or_else(|| posts.as_i64()))).unwrap_or(0) as usize)
+}
+
 pub async fn get_record(id: &ObjectId, time: &DateTime<Utc>, db: &Client) -> Result<Option<Map<String, Value>>, ActixError> {
 	get_with_query(db, doc! { "_id.id": id, "_id.t": { "$lte": time } }).await
 }
 
+pub async fn get_first_record(id: &ObjectId, db: &Client) -> Result<Option<Map<String, Value>>, ActixError> {
+	db.database(&DB_NAME)
+		.collection("objects")
+		.find_one(doc! { "_id.id": id }, FindOneOptions::builder().sort(Some(doc! { "_id.t": 1 })).build())
+		.await
+		.map_err(internal_error)?
+		.map(from_db_object)
+		.transpose()
+}
+
+// Revisions of an object, newest first. Their times are all that tells them apart.
+pub async fn get_revisions(id: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<Position>, ActixError> {
+	Ok(get_page("objects", doc! { "_id.id": id }, "_id.t", "_id.id", cursor, db).await?.into_iter().map(|(position, _)| position).collect())
+}
+
+pub async fn count_revisions(id: &ObjectId, db: &Client) -> Result<u64, ActixError> {
+	count("objects", doc! { "_id.id": id }, db).await
+}
+
 pub async fn get_replies(id: &ObjectId, db: &Client) -> Result<Vec<Map<String, Value>>, ActixError> {
-	get_all_with_query(db, doc! { "inReplyTo": id }).await
+	get_latest_with_query(db, doc! { "inReplyTo": id }).await
 }
 
 pub async fn get_children(id: &ObjectId, db: &Client) -> Result<Vec<Map<String, Value>>, ActixError> {
-	get_all_with_query(db, doc! { "context": id }).await
+	get_latest_with_query(db, doc! { "context": id }).await
+}
+
+pub async fn deliver(inbox: &ObjectId, activity: impl Into<Bson>, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("inbox")
+		.update_one(
+			doc! { "inbox": inbox, "activity": activity.into() },
+			doc! { "$setOnInsert": { "t": Utc::now() } },
+			UpdateOptions::builder().upsert(true).build()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn get_inbox(inbox: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Bson)>, ActixError> {
+	get_relation_page("inbox", doc! { "inbox": inbox }, "activity", cursor, db).await
+}
+
+pub async fn count_inbox(inbox: &ObjectId, db: &Client) -> Result<u64, ActixError> {
+	count("inbox", doc! { "inbox": inbox }, db).await
+}
+
+// Blind recipients of an object or activity. They're never served, but they may still read it.
+pub async fn insert_blind_recipients(object: &ObjectId, recipients: &[String], db: &Client) -> Result<(), ActixError> {
+	if recipients.is_empty() {
+		return Ok(());
+	}
+	db.database(&DB_NAME)
+		.collection::<Document>("blind")
+		.update_one(
+			doc! { "_id": object },
+			doc! { "$addToSet": { "recipients": { "$each": recipients } } },
+			UpdateOptions::builder().upsert(true).build()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn get_blind_recipients(object: &ObjectId, db: &Client) -> Result<Vec<String>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("blind")
+		.find_one(doc! { "_id": object }, FindOneOptions::default())
+		.await
+		.map_err(internal_error)?
+		.map_or(Ok(vec![]), |blind| {
+			blind.get_array("recipients").map_err(internal_error)?.iter().map(|recipient| recipient.as_str().map(|recipient| recipient.to_string()).ok_or_else(|| internal_error("bad recipient"))).collect()
+		})
+}
+
+// Private keys never go into `objects`, since everything there is meant to be served.
+pub async fn insert_keypair(owner: &ObjectId, public: &str, private: &str, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection("keys")
+		.insert_one(doc! { "_id": owner, "public": public, "private": private }, InsertOneOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+// Keeps a keypair that's already there, so that concurrent callers agree on one
+pub async fn insert_missing_keypair(owner: &ObjectId, public: &str, private: &str, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("keys")
+		.update_one(doc! { "_id": owner }, doc! { "$setOnInsert": { "public": public, "private": private } }, UpdateOptions::builder().upsert(true).build())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+async fn get_key(owner: &ObjectId, kind: &str, db: &Client) -> Result<Option<String>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("keys")
+		.find_one(doc! { "_id": owner }, FindOneOptions::default())
+		.await
+		.map_err(internal_error)?
+		.map(|keys| keys.get_str(kind).map(|key| key.to_string()).map_err(internal_error))
+		.transpose()
+}
+
+pub async fn get_public_key(owner: &ObjectId, db: &Client) -> Result<Option<String>, ActixError> {
+	get_key(owner, "public", db).await
+}
+
+pub async fn get_private_key(owner: &ObjectId, db: &Client) -> Result<Option<String>, ActixError> {
+	get_key(owner, "private", db).await
+}
+
+// Tokens are kept hashed, so that a leaked database doesn't let anyone act as our users.
+pub async fn insert_token(owner: &ObjectId, hash: &str, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("tokens")
+		.insert_one(doc! { "_id": hash, "owner": owner, "t": Utc::now() }, InsertOneOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn get_token_owner(hash: &str, db: &Client) -> Result<Option<ObjectId>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("tokens")
+		.find_one(doc! { "_id": hash }, FindOneOptions::default())
+		.await
+		.map_err(internal_error)?
+		.map(|token| token.get_object_id("owner").map(ObjectId::from).map_err(internal_error))
+		.transpose()
+}
+
+// Revokes a token, returning whom it was issued to
+pub async fn take_token(hash: &str, db: &Client) -> Result<Option<ObjectId>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("tokens")
+		.find_one_and_delete(doc! { "_id": hash }, FindOneAndDeleteOptions::default())
+		.await
+		.map_err(internal_error)?
+		.map(|token| token.get_object_id("owner").map(ObjectId::from).map_err(internal_error))
+		.transpose()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Job {
+	#[serde(rename = "_id")]
+	pub id: bson::oid::ObjectId,
+	// Whose key signs the delivery
+	pub actor: ObjectId,
+	pub activity: String,
+	// Either the inbox to deliver to, or recipients yet to be resolved into inboxes
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub inbox: Option<String>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub recipients: Vec<String>,
+	pub attempts: i32,
+	pub next_attempt: bson::DateTime
+}
+
+pub async fn insert_jobs(jobs: &[Job], db: &Client) -> Result<(), ActixError> {
+	if jobs.is_empty() {
+		return Ok(());
+	}
+	db.database(&DB_NAME)
+		.collection::<Job>("jobs")
+		.insert_many(jobs, InsertManyOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn get_due_jobs(limit: i64, db: &Client) -> Result<Vec<Job>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Job>("jobs")
+		.find(
+			doc! { "next_attempt": { "$lte": Utc::now() } },
+			FindOptions::builder().sort(Some(doc! { "next_attempt": 1 })).limit(Some(limit)).build()
+		)
+		.await
+		.map_err(internal_error)?
+		.map_err(internal_error)
+		.try_collect()
+		.await
+}
+
+pub async fn delete_job(id: &bson::oid::ObjectId, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Job>("jobs")
+		.delete_one(doc! { "_id": *id }, DeleteOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn reschedule_job(id: &bson::oid::ObjectId, attempts: i32, next_attempt: DateTime<Utc>, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Job>("jobs")
+		.update_one(
+			doc! { "_id": *id },
+			doc! { "$set": { "attempts": attempts, "next_attempt": next_attempt } },
+			UpdateOptions::default()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn is_dead_host(host: &str, db: &Client) -> Result<bool, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("hosts")
+		.find_one(doc! { "_id": host, "dead": true }, FindOneOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|host| host.is_some())
+}
+
+pub async fn set_host_dead(host: &str, dead: bool, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("hosts")
+		.update_one(doc! { "_id": host }, doc! { "$set": { "dead": dead } }, UpdateOptions::builder().upsert(true).build())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub const PAGE_SIZE: i64 = 20;
+
+// Where an item is in a collection. Items of the same time are ordered by id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+	pub time: DateTime<Utc>,
+	pub id: bson::oid::ObjectId
+}
+
+// Position in a collection ordered newest first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cursor {
+	Newest,
+	Before(Position),
+	After(Position)
+}
+
+// A field of a document, where `path` may go into embedded documents with dots like in queries
+fn get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+	let mut parts = path.split('.');
+	let mut value = doc.get(parts.next()?)?;
+	for part in parts {
+		value = value.as_document()?.get(part)?;
+	}
+	Some(value)
+}
+
+// Items past `position` in the direction of `comparison`, either `$lt` or `$gt`
+fn past(position: Position, comparison: &str, time_field: &str, id_field: &str) -> Document {
+	doc! { "$or": [{ time_field: { comparison: position.time } }, { time_field: position.time, id_field: { comparison: position.id } }] }
+}
+
+// Returns a page of `collection` ordered by `time_field` then `id_field`, newest first.
+async fn get_page(collection: &str, query: Document, time_field: &str, id_field: &str, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Document)>, ActixError> {
+	let (query, order) = match cursor {
+		Cursor::Newest => (query, -1),
+		Cursor::Before(position) => (doc! { "$and": [query, past(position, "$lt", time_field, id_field)] }, -1),
+		Cursor::After(position) => (doc! { "$and": [query, past(position, "$gt", time_field, id_field)] }, 1)
+	};
+	let mut page = db
+		.database(&DB_NAME)
+		.collection::<Document>(collection)
+		.find(query, FindOptions::builder().sort(Some(doc! { time_field: order, id_field: order })).limit(Some(PAGE_SIZE)).build())
+		.await
+		.map_err(internal_error)?
+		.map_err(internal_error)
+		.and_then(|doc| async move {
+			let time = get_path(&doc, time_field).and_then(Bson::as_datetime).ok_or_else(|| internal_error(format!("`{}` is missing", time_field)))?.to_chrono();
+			let id = get_path(&doc, id_field).and_then(Bson::as_object_id).ok_or_else(|| internal_error(format!("`{}` is missing", id_field)))?;
+			Ok((Position { time, id }, doc))
+		})
+		.try_collect::<Vec<_>>()
+		.await?;
+	if order == 1 {
+		page.reverse();
+	}
+	Ok(page)
+}
+
+async fn count(collection: &str, query: Document, db: &Client) -> Result<u64, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>(collection)
+		.count_documents(query, CountOptions::default())
+		.await
+		.map_err(internal_error)
+}
+
+// Returns (time, reference) pairs from a relation, such as followers of someone.
+async fn get_relation_page(collection: &str, query: Document, field: &str, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Bson)>, ActixError> {
+	get_page(collection, query, "t", "_id", cursor, db)
+		.await?
+		.into_iter()
+		.map(|(position, mut doc)| Ok((position, doc.remove(field).ok_or_else(|| internal_error(format!("`{}` is missing", field)))?)))
+		.collect()
+}
+
+pub async fn insert_follow(follower: Bson, followee: Bson, activity: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("follows")
+		.update_one(
+			doc! { "follower": follower, "followee": followee },
+			doc! { "$set": { "activity": activity }, "$setOnInsert": { "accepted": false, "t": Utc::now() } },
+			UpdateOptions::builder().upsert(true).build()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+// Returns the follower if there was such a follow request.
+pub async fn accept_follow(activity: Bson, followee: Bson, db: &Client) -> Result<Option<Bson>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("follows")
+		.find_one_and_update(
+			doc! { "activity": activity, "followee": followee },
+			doc! { "$set": { "accepted": true, "t": Utc::now() } },
+			FindOneAndUpdateOptions::default()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|follow| follow.and_then(|mut follow| follow.remove("follower")))
+}
+
+// Returns the follower if there was such a follow request.
+pub async fn reject_follow(activity: Bson, followee: Bson, db: &Client) -> Result<Option<Bson>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("follows")
+		.find_one_and_delete(doc! { "activity": activity, "followee": followee }, FindOneAndDeleteOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|follow| follow.and_then(|mut follow| follow.remove("follower")))
+}
+
+pub async fn delete_follow(activity: Bson, follower: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("follows")
+		.delete_one(doc! { "activity": activity, "follower": follower }, DeleteOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn get_followers(followee: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Bson)>, ActixError> {
+	get_relation_page("follows", doc! { "followee": followee, "accepted": true }, "follower", cursor, db).await
+}
+
+pub async fn get_all_followers(followee: &ObjectId, db: &Client) -> Result<Vec<Bson>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("follows")
+		.distinct("follower", doc! { "followee": followee, "accepted": true }, DistinctOptions::default())
+		.await
+		.map_err(internal_error)
+}
+
+// Our users following someone, who may be remote
+pub async fn get_local_followers(followee: Bson, db: &Client) -> Result<Vec<Bson>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("follows")
+		.distinct("follower", doc! { "followee": followee, "accepted": true, "follower": { "$type": "objectId" } }, DistinctOptions::default())
+		.await
+		.map_err(internal_error)
+}
+
+pub async fn count_followers(followee: &ObjectId, db: &Client) -> Result<u64, ActixError> {
+	count("follows", doc! { "followee": followee, "accepted": true }, db).await
+}
+
+pub async fn is_follower(follower: Bson, followee: &ObjectId, db: &Client) -> Result<bool, ActixError> {
+	count("follows", doc! { "follower": follower, "followee": followee, "accepted": true }, db).await.map(|count| count > 0)
+}
+
+pub async fn get_following(follower: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Bson)>, ActixError> {
+	get_relation_page("follows", doc! { "follower": follower, "accepted": true }, "followee", cursor, db).await
+}
+
+pub async fn count_following(follower: &ObjectId, db: &Client) -> Result<u64, ActixError> {
+	count("follows", doc! { "follower": follower, "accepted": true }, db).await
+}
+
+// Liking something twice changes nothing. Returns the earlier Like activity in that case.
+pub async fn insert_like(actor: Bson, object: Bson, activity: Bson, db: &Client) -> Result<Option<Bson>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("likes")
+		.find_one_and_update(
+			doc! { "actor": actor, "object": object },
+			doc! { "$setOnInsert": { "activity": activity, "t": Utc::now() } },
+			FindOneAndUpdateOptions::builder().upsert(true).build()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|like| like.and_then(|mut like| like.remove("activity")))
+}
+
+pub async fn delete_like(activity: Bson, actor: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("likes")
+		.delete_one(doc! { "activity": activity, "actor": actor }, DeleteOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn get_likes(object: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Bson)>, ActixError> {
+	get_relation_page("likes", doc! { "object": object }, "actor", cursor, db).await
+}
+
+pub async fn count_likes(object: &ObjectId, db: &Client) -> Result<u64, ActixError> {
+	count("likes", doc! { "object": object }, db).await
+}
+
+pub async fn get_liked(actor: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Bson)>, ActixError> {
+	get_relation_page("likes", doc! { "actor": actor }, "object", cursor, db).await
+}
+
+pub async fn count_liked(actor: &ObjectId, db: &Client) -> Result<u64, ActixError> {
+	count("likes", doc! { "actor": actor }, db).await
+}
+
+// Collection members are kept apart from the collection, so that changing them doesn't need a new revision of it.
+pub async fn insert_item(collection: &ObjectId, item: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("items")
+		.update_one(
+			doc! { "collection": collection, "item": item },
+			doc! { "$setOnInsert": { "t": Utc::now() } },
+			UpdateOptions::builder().upsert(true).build()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn delete_item(collection: &ObjectId, item: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("items")
+		.delete_one(doc! { "collection": collection, "item": item }, DeleteOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn get_items(collection: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Bson)>, ActixError> {
+	get_relation_page("items", doc! { "collection": collection }, "item", cursor, db).await
+}
+
+pub async fn count_items(collection: &ObjectId, db: &Client) -> Result<u64, ActixError> {
+	count("items", doc! { "collection": collection }, db).await
+}
+
+pub async fn insert_share(actor: Bson, object: Bson, activity: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("shares")
+		.update_one(
+			doc! { "activity": activity, "object": object },
+			doc! { "$setOnInsert": { "actor": actor, "t": Utc::now() } },
+			UpdateOptions::builder().upsert(true).build()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn delete_share(activity: Bson, actor: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("shares")
+		.delete_one(doc! { "activity": activity, "actor": actor }, DeleteOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+// Shares are the Announce activities themselves
+pub async fn get_shares(object: &ObjectId, cursor: Cursor, db: &Client) -> Result<Vec<(Position, Bson)>, ActixError> {
+	get_relation_page("shares", doc! { "object": object }, "activity", cursor, db).await
+}
+
+pub async fn count_shares(object: &ObjectId, db: &Client) -> Result<u64, ActixError> {
+	count("shares", doc! { "object": object }, db).await
+}
+
+pub async fn insert_block(actor: Bson, object: Bson, activity: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("blocks")
+		.update_one(
+			doc! { "actor": actor, "object": object },
+			doc! { "$setOnInsert": { "activity": activity, "t": Utc::now() } },
+			UpdateOptions::builder().upsert(true).build()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+pub async fn delete_block(activity: Bson, actor: Bson, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("blocks")
+		.delete_one(doc! { "activity": activity, "actor": actor }, DeleteOptions::default())
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
+}
+
+// Whether any of `actors` blocks `object`
+pub async fn is_blocked(actors: Vec<Bson>, object: Bson, db: &Client) -> Result<bool, ActixError> {
+	count("blocks", doc! { "actor": { "$in": actors }, "object": object }, db).await.map(|count| count > 0)
+}
+
+// Everyone blocked by any of `actors`
+pub async fn get_blocked(actors: Vec<Bson>, db: &Client) -> Result<Vec<Bson>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("blocks")
+		.distinct("object", doc! { "actor": { "$in": actors } }, DistinctOptions::default())
+		.await
+		.map_err(internal_error)
+}
+
+// Remote objects are kept as JSON text, since their keys may not be valid as BSON field names.
+pub async fn get_cached(iri: &str, db: &Client) -> Result<Option<Map<String, Value>>, ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("remote")
+		.find_one(doc! { "_id": iri }, FindOneOptions::default())
+		.await
+		.map_err(internal_error)?
+		.map(|cached| serde_json::from_str(cached.get_str("object").map_err(internal_error)?).map_err(internal_error))
+		.transpose()
+}
+
+pub async fn cache_remote(iri: &str, object: &Map<String, Value>, db: &Client) -> Result<(), ActixError> {
+	db.database(&DB_NAME)
+		.collection::<Document>("remote")
+		.replace_one(
+			doc! { "_id": iri },
+			doc! { "_id": iri, "object": serde_json::to_string(object).map_err(internal_error)?, "fetched": Utc::now() },
+			ReplaceOptions::builder().upsert(true).build()
+		)
+		.await
+		.map_err(internal_error)
+		.map(|_| ())
 }
 
 fn from_db_object(mut doc: Document) -> Result<Map<String, Value>, ActixError> {
+	// Objects stored before blind recipients were kept apart may still have them
+	doc.remove("bto");
+	doc.remove("bcc");
 	if let Bson::Document(mut _id) = doc.remove("_id").ok_or_else(|| internal_error("`_id` is missing"))? {
 		doc.insert("id", _id.remove("id").ok_or_else(|| internal_error("`_id.id` is missing"))?);
 		doc.insert("updated", _id.remove("t").ok_or_else(|| internal_error("`_id.t` is missing"))?);
@@ -1,9 +1,31 @@
-use std::fmt::{Debug, Display};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
-use actix_web::error::{Error, InternalError};
+use actix_web::error::{Error, InternalError, ResponseError};
+use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
+use serde_json::{Map, Value};
 
 pub fn internal_error(err: impl Debug + Display + 'static) -> Error {
 	log::error!("{}", err);
 	InternalError::from_response(err, HttpResponse::InternalServerError().finish()).into()
 }
+
+// A deleted object, along with its Tombstone to serve
+#[derive(Debug)]
+pub struct Gone(pub Map<String, Value>);
+
+impl Display for Gone {
+	fn fmt(&self, f: &mut Formatter) -> FmtResult {
+		write!(f, "the object has been deleted")
+	}
+}
+
+impl ResponseError for Gone {
+	fn status_code(&self) -> StatusCode {
+		StatusCode::GONE
+	}
+
+	fn error_response(&self) -> HttpResponse {
+		HttpResponse::Gone().json(&self.0)
+	}
+}
@@ -24,8 +24,9 @@ mod db;
 mod error;
 mod util;
 mod web;
+mod wellknown;
 
-use activitypub::is_activitypub_request;
+use activitypub::{is_activitypub_request, is_datetime_negotiation, RECORD_PATH};
 
 use const_format::concatcp;
 
@@ -34,6 +35,15 @@ const PORT: &str = "8088";
 const HOST_PORT: &str = concatcp!(HOST, ":", PORT);
 
 static DB_NAME: Lazy<String> = Lazy::new(|| env("DB_NAME").unwrap());
+static SSL: Lazy<bool> = Lazy::new(|| {
+	env("SSL").map_or(true, |ssl| match ssl.as_str() {
+		"false" | "0" => false,
+		_ => true
+	})
+});
+
+// Ids of accounts which may modify anything, separated by commas
+static ADMINS: Lazy<Vec<String>> = Lazy::new(|| env("ADMINS").map_or(vec![], |admins| admins.split(',').map(|admin| admin.trim().to_string()).collect()));
 
 static SEND_TO_JS_THREAD: OnceCell<Mutex<mpsc::Sender<(String, Map<String, Value>, oneshot::Sender<Result<web::GeneratedHtml, deno_core::anyhow::Error>>)>>> = OnceCell::new();
 
@@ -45,12 +55,17 @@ fn global_setup() {
 
 #[actix_web::main]
 async fn main() {
-	let ssl = env("SSL").map_or(true, |ssl| match ssl.as_str() {
-		"false" | "0" => false,
-		_ => true
-	});
-
 	let db = mongodb::Client::with_uri_str(&env("CLUSTER_URI").unwrap()).await.unwrap();
+	db::setup(&db).await.unwrap();
+
+	// For accounts which have no token, or lost it
+	if let [_, command, id] = &std::env::args().collect::<Vec<_>>()[..] {
+		if command == "issue-token" {
+			let token = activitypub::issue_token(&id.parse().expect("account id"), &db).await.unwrap();
+			println!("{}", token);
+			return;
+		}
+	}
 
 	let mut deno = MainWorker::bootstrap_from_options(
 		Url::parse("graft:svelte_entry_point").unwrap(),
@@ -105,6 +120,8 @@ async fn main() {
 		}
 	});
 
+	spawn(activitypub::run_queue(db.clone()));
+
 	let mut server = HttpServer::new(move || {
 		// About actix-web:
 		// scopes concat before resource URL.
@@ -136,7 +153,11 @@ async fn main() {
 				.route(get().to(auth::get_auth))
 				.route(post().to(auth::post_auth)),
 			)*/
+			.route("/.well-known/webfinger", get().to(wellknown::webfinger))
+			.route("/.well-known/nodeinfo", get().to(wellknown::nodeinfo_links))
+			.service(resource("/nodeinfo/2.1").name("nodeinfo").route(get().to(wellknown::nodeinfo)))
 			.route("/new-account", post().to(activitypub::create_account))
+			.route("/token", post().to(activitypub::reissue_token))
 			.service(resource("/of/{url_decoration:([^-/]+-)?}{id:[^-/]+}")
 				.name("account")
 				.route(get().guard(is_activitypub_request).to(activitypub::account))
@@ -146,11 +167,13 @@ async fn main() {
 				.route(get().guard(is_activitypub_request).to(activitypub::outbox))
 				.route(post().guard(is_activitypub_request).to(activitypub::submit))
 			).service(scope("/post")
-				.service(resource(r"{url_decoration:([^-/]+-)?}{time:\d{4}-\d\d-\d\dT\d\d:\d\d(:\d\d(\.\d+)?)?Z}-{id:[^-/]+}")
+				.service(resource(RECORD_PATH)
+					.name("record")
 					.route(get().guard(guard::Not(is_activitypub_request)).to(web::record))
 					.route(get().guard(is_activitypub_request).to(activitypub::record))
 				).service(resource("{url_decoration:([^-/]+-)?}{id:[^-/]+}")
 					.name("post")
+					.route(get().guard(is_datetime_negotiation).to(activitypub::time_gate))
 					.route(get().guard(guard::Not(is_activitypub_request)).to(web::post))
 					.route(get().guard(is_activitypub_request).to(activitypub::post))
 				)
@@ -158,19 +181,27 @@ async fn main() {
 				.name("activity")
 				.route(get().guard(is_activitypub_request).to(activitypub::activity))
 			)
+			.route("/followers/{id:[^-/]+}", get().guard(is_activitypub_request).to(activitypub::followers))
+			.route("/following/{id:[^-/]+}", get().guard(is_activitypub_request).to(activitypub::following))
+			.route("/liked/{id:[^-/]+}", get().guard(is_activitypub_request).to(activitypub::liked))
+			.service(resource("/likes/{id:[^-/]+}").name("likes").route(get().guard(is_activitypub_request).to(activitypub::likes)))
+			.service(resource("/shares/{id:[^-/]+}").name("shares").route(get().guard(is_activitypub_request).to(activitypub::shares)))
 			// There shouldn't be any situation where the user wants link to list of revisions.
 			// As such, no HTML serving handler or URL decoration is implemented for changelogs.
 			// Web client must use Javascript(and/or WebAssembly) to fetch them via ActivityPub interface and show.
 			.route("/log/{id}", get().guard(is_activitypub_request).to(activitypub::get_changelog))
+			.service(resource("/timemap/{id:[^-/]+}").name("timemap").route(get().to(activitypub::timemap)))
+			.service(resource("/diff/{id:[^-/]+}").name("diff").route(get().guard(is_activitypub_request).to(activitypub::get_diff)))
+			.route("/inbox", post().guard(is_activitypub_request).to(activitypub::shared_delivery))
 			.service(resource("/for/{url_decoration:([^-/]+-)?}{id:[^-/]+}")
 				.name("inbox")
 				.route(get().guard(guard::Not(is_activitypub_request)).to(web::mentions))
 				.route(get().guard(is_activitypub_request).to(activitypub::inbox))
-				//.route(post().guard(is_activitypub_request).to(activitypub::delivery))
+				.route(post().guard(is_activitypub_request).to(activitypub::delivery))
 			)
 	});
 
-	if ssl {
+	if *SSL {
 		// load ssl keys
 		let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
 		loop {
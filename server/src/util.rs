@@ -5,9 +5,10 @@ use std::str::FromStr;
 
 use actix_web::dev::Payload;
 use actix_web::{error, FromRequest, HttpRequest};
+use chrono::{DateTime, Utc};
 use mongodb::bson::{self, Bson};
 use once_cell::sync::Lazy;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use url::Url as NativeUrl;
 
@@ -66,7 +67,7 @@ impl FromRequest for Url {
 // url_for_id(req, id)
 // }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct ObjectId(bson::oid::ObjectId);
 
 impl ToString for ObjectId {
@@ -115,6 +116,12 @@ impl FromRequest for ObjectId {
 	}
 }
 
+impl From<bson::oid::ObjectId> for ObjectId {
+	fn from(id: bson::oid::ObjectId) -> Self {
+		ObjectId(id)
+	}
+}
+
 impl From<&ObjectId> for Bson {
 	fn from(id: &ObjectId) -> Self {
 		// Bson::Binary(Binary { subtype: mongodb::bson::spec::BinarySubtype::Uuid, bytes: (id.0.as_bytes() as &[u8]).into() })
@@ -128,6 +135,23 @@ impl From<&ObjectId> for Value {
 	}
 }
 
+// Relations refer to objects on this server by their oid, and to the others by their IRI.
+pub fn to_reference(iri: &str) -> Bson {
+	match iri.parse::<Url>().map_err(error::ErrorBadRequest).and_then(ObjectId::try_from) {
+		Ok(id) => (&id).into(),
+		Err(_) => Bson::String(iri.to_string())
+	}
+}
+
+// `prefix` is the path local objects are served on, e.g. "of" for actors.
+pub fn from_reference(reference: &Bson, prefix: &str) -> Option<String> {
+	match reference {
+		Bson::ObjectId(id) => Some(format!("{}/{}/{}", *ORIGIN, prefix, id.to_hex())),
+		Bson::String(iri) => Some(iri.clone()),
+		_ => None
+	}
+}
+
 pub fn get_oid(url: &str) -> Option<&str> {
 	url.rfind('-').or(url.rfind('/')).map(|i| &url[(i + 1)..])
 }
@@ -137,6 +161,14 @@ pub fn generate_id() -> ObjectId {
 	ObjectId(bson::oid::ObjectId::new())
 }
 
+// Where this server is reachable, for building URLs outside of requests
+pub static ORIGIN: Lazy<String> = Lazy::new(|| format!("{}://{}", if *crate::SSL { "https" } else { "http" }, crate::HOST_PORT));
+
+// RFC 7231 IMF-fixdate, as used in `Date` header
+pub fn http_date(time: DateTime<Utc>) -> String {
+	time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
 static BASE_DIR: Lazy<&'static str> = Lazy::new(|| {
 	let exec_dir = std::env::current_exe().unwrap().parent().unwrap().to_path_buf();
 	let base_dir_candidate = exec_dir.join("ssr/");
@@ -1,7 +1,7 @@
 use futures::SinkExt;
 
 use actix_web::error::Error as ActixError;
-use actix_web::Responder;
+use actix_web::{CustomizeResponder, Responder};
 use futures::channel::oneshot::channel;
 use serde_json::{Map, Value};
 
@@ -22,7 +22,7 @@ pub struct GeneratedHtml {
 	html: String
 }
 
-async fn render(name: &'static str, prop: Map<String, Value>) -> Result<impl Responder, ActixError> {
+async fn render(name: &'static str, prop: Map<String, Value>) -> Result<CustomizeResponder<String>, ActixError> {
 	let (tx, rx) = channel();
 	SEND_TO_JS_THREAD
 		.get()
@@ -1,20 +1,38 @@
 use actix_web::error::Error as ActixError;
+use actix_web::http::StatusCode;
 use actix_web::{HttpRequest, Responder};
+use serde_json::Value;
 
 use super::render;
 use crate::activitypub;
-use crate::util::call_handler;
+use crate::error::Gone;
+use crate::util::{call_handler, ObjectId};
 
 pub async fn account() -> Result<String, ActixError> {
 	todo!()
 }
 
-pub async fn post(req: HttpRequest) -> Result<impl Responder, ActixError> {
-	render("Main", call_handler(activitypub::post, &req).await??.into_inner()).await
+pub async fn post(req: HttpRequest, id: ObjectId) -> Result<impl Responder, ActixError> {
+	match call_handler(activitypub::get_post, &req).await? {
+		Ok(post) => Ok(render("Main", post.into_inner())
+			.await?
+			.insert_header(("Vary", "Accept-Datetime"))
+			.insert_header(("Link", activitypub::memento_links(&req, &id)?))),
+		Err(err) => match err.as_error::<Gone>() {
+			Some(Gone(tombstone)) => Ok(render("Deleted", tombstone.clone()).await?.with_status(StatusCode::GONE)),
+			None => Err(err)
+		}
+	}
 }
 
-pub async fn record() -> Result<String, ActixError> {
-	todo!()
+pub async fn record(req: HttpRequest, id: ObjectId) -> Result<impl Responder, ActixError> {
+	let (post, memento_datetime) = call_handler(activitypub::get_memento, &req).await??;
+	// A record of a deletion is just as much a memento as any other
+	let component = if post.get("type").and_then(Value::as_str) == Some("Tombstone") { "Deleted" } else { "Main" };
+	Ok(render(component, post)
+		.await?
+		.insert_header(("Memento-Datetime", memento_datetime))
+		.insert_header(("Link", activitypub::memento_links(&req, &id)?)))
 }
 
 pub async fn mentions() -> Result<String, ActixError> {
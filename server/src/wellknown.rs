@@ -0,0 +1,118 @@
+use std::convert::TryFrom;
+
+use actix_web::error::{Error as ActixError, ErrorBadRequest, ErrorNotFound};
+use actix_web::web::{Data, Query};
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use mongodb::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::db::{count_active_actors, count_local_actors, count_local_posts, get, get_actor_by_name, ACTOR_TYPES};
+use crate::error::internal_error;
+use crate::util::{ObjectId, Url};
+
+#[derive(Deserialize)]
+pub struct WebfingerQuery {
+	resource: String
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Resource<'a> {
+	Account { name: &'a str, host: &'a str },
+	Url(&'a str)
+}
+
+fn parse_resource(resource: &str) -> Result<Resource, ActixError> {
+	if let Some(account) = resource.strip_prefix("acct:") {
+		let at = account.rfind('@').ok_or(ErrorBadRequest("`acct` URI must have a host"))?;
+		Ok(Resource::Account { name: account[..at].trim_start_matches('@'), host: &account[(at + 1)..] })
+	} else if resource.starts_with("https:") || resource.starts_with("http:") {
+		Ok(Resource::Url(resource))
+	} else {
+		Err(ErrorBadRequest("unsupported resource"))
+	}
+}
+
+// RFC 7033, as used by Mastodon and friends to look up `acct:name@host`
+pub async fn webfinger(req: HttpRequest, query: Query<WebfingerQuery>, db: Data<Client>) -> Result<HttpResponse, ActixError> {
+	let host = req.connection_info().host().to_string();
+	let actor = match parse_resource(&query.resource)? {
+		Resource::Account { name, host: account_host } => {
+			if account_host != host && account_host != crate::HOST && account_host != crate::HOST_PORT {
+				return Err(ErrorNotFound(""));
+			}
+			get_actor_by_name(name, &db).await?
+		}
+		Resource::Url(url) => {
+			let id = url.parse::<Url>().map_err(ErrorBadRequest).and_then(ObjectId::try_from).map_err(|_| ErrorNotFound(""))?;
+			get(&id, &db).await?
+		}
+	}
+	.filter(|actor| actor.get("type").and_then(|ty| ty.as_str()).map_or(false, |ty| ACTOR_TYPES.contains(&ty)))
+	.ok_or(ErrorNotFound(""))?;
+
+	let id = actor["id"].as_str().expect("id is converted to string");
+	let name = actor.get("name").and_then(|name| name.as_str());
+	let url = req.url_for("account", [name.map_or(String::new(), |name| name.to_string() + "-").as_str(), id]).map_err(internal_error)?.to_string();
+	let subject = match name {
+		Some(name) => format!("acct:{}@{}", name, host),
+		None => url.clone()
+	};
+	Ok(HttpResponse::Ok().content_type("application/jrd+json").json(json!({
+		"subject": subject,
+		"aliases": [url],
+		"links": [
+			{ "rel": "self", "type": "application/activity+json", "href": url },
+			{ "rel": "http://webfinger.net/rel/profile-page", "type": "text/html", "href": url }
+		]
+	})))
+}
+
+const NODEINFO_SCHEMA: &str = "http://nodeinfo.diaspora.software/ns/schema/2.1";
+
+pub async fn nodeinfo_links(req: HttpRequest) -> Result<HttpResponse, ActixError> {
+	Ok(HttpResponse::Ok().json(json!({
+		"links": [
+			{ "rel": NODEINFO_SCHEMA, "href": req.url_for_static("nodeinfo").map_err(internal_error)?.to_string() }
+		]
+	})))
+}
+
+pub async fn nodeinfo(db: Data<Client>) -> Result<HttpResponse, ActixError> {
+	let now = Utc::now();
+	Ok(HttpResponse::Ok().content_type(format!(r#"application/json; profile="{}#""#, NODEINFO_SCHEMA)).json(json!({
+		"version": "2.1",
+		"software": {
+			"name": "graft",
+			"version": env!("CARGO_PKG_VERSION"),
+			"repository": env!("CARGO_PKG_REPOSITORY")
+		},
+		"protocols": ["activitypub"],
+		"services": { "inbound": [], "outbound": [] },
+		"openRegistrations": true,
+		"usage": {
+			"users": {
+				"total": count_local_actors(&db).await?,
+				"activeMonth": count_active_actors(now - Duration::days(30), &db).await?,
+				"activeHalfyear": count_active_actors(now - Duration::days(180), &db).await?
+			},
+			"localPosts": count_local_posts(&db).await?
+		},
+		"metadata": {}
+	})))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_resource() {
+		assert_eq!(parse_resource("acct:alice@example.org").unwrap(), Resource::Account { name: "alice", host: "example.org" });
+		assert_eq!(parse_resource("acct:@alice@example.org").unwrap(), Resource::Account { name: "alice", host: "example.org" });
+		assert_eq!(parse_resource("https://example.org/of/alice-abc").unwrap(), Resource::Url("https://example.org/of/alice-abc"));
+		assert!(parse_resource("acct:alice").is_err());
+		assert!(parse_resource("mailto:alice@example.org").is_err());
+	}
+}
@@ -4,7 +4,8 @@ import css from 'rollup-plugin-css-only';
 import resolve from '@rollup/plugin-node-resolve';
 
 const input = [
-  'src/Main.svelte'
+  'src/Main.svelte',
+  'src/Deleted.svelte'
 ];
 
 export default [